@@ -3,6 +3,10 @@ sudo systemctl stop frust
 sudo systemctl start frust
 sudo systemctl status frust.service
 
-sudo systemctl stop alarm
-sudo systemctl start alarm
-sudo systemctl status alarm
\ No newline at end of file
+# alarm.sh is replaced by the alarms in config.json, see "Migrating from alarm.sh" in the README
+if systemctl cat alarm.service > /dev/null 2>&1; then
+  sudo systemctl disable --now alarm
+fi
+if ! grep -q '"command"' config.json; then
+  echo "Warning: config.json has no alarms.command, alarms are only logged"
+fi
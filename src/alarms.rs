@@ -0,0 +1,573 @@
+//! Alarms on the status of the controller.
+//! Every rule is checked on each control tick. A rule whose condition holds for
+//! its debounce time raises an alarm, which sends a notification and repeats it
+//! every notify interval until the alarm is acknowledged or clears.
+use std::{collections::HashSet, fs::File, path::Path, process::Command, thread};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::controller::{FridgeStatus, OperationMode};
+
+/// Condition of an alarm, temperatures in °C
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+pub enum AlarmRule {
+    // Temperature of a sensor, the inside temperature without one, below min or above max
+    Bounds {
+        #[serde(default)]
+        sensor: Option<String>,
+        #[serde(default)]
+        min: Option<f64>,
+        #[serde(default)]
+        max: Option<f64>,
+    },
+    // Inside temperature further than max_deviation from the target temperature
+    Deviation {
+        max_deviation: f64,
+    },
+    // A sensor, any sensor without one, has not been read for its stale timeout
+    SensorStale {
+        #[serde(default)]
+        sensor: Option<String>,
+    },
+    // The diagnostics found a fault
+    Fault,
+    // The door is open for longer than its left_open_ms
+    DoorLeftOpen,
+    // The controller started after it stopped without shutting down, until acknowledged
+    Restart,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AlarmConfig {
+    pub name: String,
+
+    #[serde(flatten)]
+    pub rule: AlarmRule,
+
+    // Time the condition must hold before the alarm is raised
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: f64,
+
+    // Minimum time between notifications of the alarm
+    #[serde(default = "default_notify_interval_ms")]
+    pub notify_interval_ms: f64,
+}
+
+fn default_debounce_ms() -> f64 {
+    60000.0
+}
+
+fn default_notify_interval_ms() -> f64 {
+    // Five minutes, like the SMS of the old alarm script
+    300000.0
+}
+
+impl AlarmConfig {
+    fn new(name: &str, rule: AlarmRule, debounce_ms: f64) -> AlarmConfig {
+        AlarmConfig {
+            name: name.to_string(),
+            rule,
+            debounce_ms,
+            notify_interval_ms: default_notify_interval_ms(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct AlarmsConfig {
+    pub rules: Vec<AlarmConfig>,
+
+    // Program and arguments that send a notification, the message is added as last argument
+    pub command: Option<Vec<String>>,
+}
+
+impl Default for AlarmsConfig {
+    fn default() -> AlarmsConfig {
+        AlarmsConfig {
+            rules: vec![
+                AlarmConfig::new("restart", AlarmRule::Restart, 0.0),
+                AlarmConfig::new("fault", AlarmRule::Fault, 0.0),
+                AlarmConfig::new(
+                    "sensor_stale",
+                    AlarmRule::SensorStale { sensor: None },
+                    default_debounce_ms(),
+                ),
+                // Only fires with a door switch, left_open_ms is its debounce
+                AlarmConfig::new("door_left_open", AlarmRule::DoorLeftOpen, 0.0),
+            ],
+            command: None,
+        }
+    }
+}
+
+impl AlarmsConfig {
+    pub fn validate(&self) -> Result<()> {
+        let mut names = HashSet::new();
+        for alarm in &self.rules {
+            if alarm.name.is_empty() {
+                bail!("alarms need a name");
+            }
+            if !names.insert(&alarm.name) {
+                bail!("alarm {} is configured twice", alarm.name);
+            }
+            if !alarm.debounce_ms.is_finite() || alarm.debounce_ms < 0.0 {
+                bail!("debounce_ms of alarm {} must be at least 0", alarm.name);
+            }
+            if !alarm.notify_interval_ms.is_finite() || alarm.notify_interval_ms <= 0.0 {
+                bail!(
+                    "notify_interval_ms of alarm {} must be larger than 0",
+                    alarm.name
+                );
+            }
+            match &alarm.rule {
+                AlarmRule::Bounds { min, max, .. } => {
+                    if min.is_none() && max.is_none() {
+                        bail!("alarm {} needs a min or max", alarm.name);
+                    }
+                    if min.iter().chain(max).any(|value| !value.is_finite()) {
+                        bail!("min and max of alarm {} must be numbers", alarm.name);
+                    }
+                    if let (Some(min), Some(max)) = (min, max) {
+                        if min >= max {
+                            bail!("min of alarm {} must be below its max", alarm.name);
+                        }
+                    }
+                }
+                AlarmRule::Deviation { max_deviation }
+                    if !max_deviation.is_finite() || *max_deviation <= 0.0 =>
+                {
+                    bail!(
+                        "max_deviation of alarm {} must be larger than 0",
+                        alarm.name
+                    );
+                }
+                _ => {}
+            }
+        }
+        if self.command.as_ref().is_some_and(Vec::is_empty) {
+            bail!("the alarm command needs a program");
+        }
+        Ok(())
+    }
+}
+
+/// State of the alarm of a rule
+#[derive(Debug, Clone, Serialize)]
+pub struct AlarmStatus {
+    pub name: String,
+
+    // Reason while the condition holds
+    pub message: Option<String>,
+
+    // Time the condition has held (ms)
+    pub condition_ms: f64,
+
+    // The condition held for the debounce time and has not cleared since
+    pub active: bool,
+
+    // Time the alarm was raised
+    pub since: Option<DateTime<Utc>>,
+
+    // No more notifications until the alarm is raised again
+    pub acknowledged: bool,
+
+    pub notifications: u32,
+
+    pub last_notification: Option<DateTime<Utc>>,
+
+    // Time since the last notification (ms), kept when the alarm clears to limit the rate
+    #[serde(skip)]
+    since_notification_ms: Option<f64>,
+}
+
+impl AlarmStatus {
+    fn new(name: &str) -> AlarmStatus {
+        AlarmStatus {
+            name: name.to_string(),
+            message: None,
+            condition_ms: 0.0,
+            active: false,
+            since: None,
+            acknowledged: false,
+            notifications: 0,
+            last_notification: None,
+            since_notification_ms: None,
+        }
+    }
+
+    /// Stop the notifications of an active alarm, returns false when it is not active
+    pub fn acknowledge(&mut self) -> bool {
+        if !self.active {
+            return false;
+        }
+        if !self.acknowledged {
+            info!("Alarm {} acknowledged", self.name);
+            self.acknowledged = true;
+        }
+        true
+    }
+}
+
+/// Mark the controller as running with a file at `path`, which is removed on a clean
+/// shutdown. Returns whether the previous run left it behind.
+pub fn mark_running(path: &Path) -> Result<bool> {
+    let restarted = path.exists();
+    File::create(path).with_context(|| format!("could not create {}", path.display()))?;
+    Ok(restarted)
+}
+
+/// Alarms of the configured rules, in their order
+#[derive(Debug)]
+pub struct Alarms {
+    alarms: Vec<AlarmStatus>,
+
+    // The previous run did not shut down
+    restarted: bool,
+}
+
+impl Alarms {
+    pub fn new(restarted: bool) -> Alarms {
+        Alarms {
+            alarms: Vec::new(),
+            restarted,
+        }
+    }
+
+    pub fn alarms(&self) -> &[AlarmStatus] {
+        &self.alarms
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut AlarmStatus> {
+        self.alarms.iter_mut().find(|alarm| alarm.name == name)
+    }
+
+    /// Names of the active alarms
+    pub fn active(&self) -> Vec<String> {
+        self.alarms
+            .iter()
+            .filter(|alarm| alarm.active)
+            .map(|alarm| alarm.name.clone())
+            .collect()
+    }
+
+    /// Check the rules against the status of a step that took `delta_ms`,
+    /// returns whether an alarm was raised or cleared
+    pub fn update(&mut self, status: &FridgeStatus, config: &AlarmsConfig, delta_ms: f64) -> bool {
+        // Follow changes of the rules, alarms keep their state by name
+        let mut previous = std::mem::take(&mut self.alarms);
+        self.alarms = config
+            .rules
+            .iter()
+            .map(
+                |rule| match previous.iter().position(|a| a.name == rule.name) {
+                    Some(index) => previous.swap_remove(index),
+                    None => AlarmStatus::new(&rule.name),
+                },
+            )
+            .collect();
+
+        let mut changed = false;
+        for (rule, alarm) in config.rules.iter().zip(&mut self.alarms) {
+            if rule.rule == AlarmRule::Restart && alarm.acknowledged {
+                self.restarted = false;
+            }
+            match condition(&rule.rule, status, self.restarted) {
+                Some(message) => {
+                    alarm.condition_ms += delta_ms;
+                    if !alarm.active && alarm.condition_ms >= rule.debounce_ms {
+                        warn!("Alarm {}: {}", alarm.name, message);
+                        alarm.active = true;
+                        alarm.since = Some(Utc::now());
+                        changed = true;
+                    }
+                    alarm.message = Some(message);
+                }
+                None => {
+                    if alarm.active {
+                        info!("Alarm {} cleared", alarm.name);
+                        alarm.active = false;
+                        alarm.since = None;
+                        alarm.acknowledged = false;
+                        changed = true;
+                    }
+                    alarm.condition_ms = 0.0;
+                    alarm.message = None;
+                }
+            }
+
+            if let Some(ms) = alarm.since_notification_ms.as_mut() {
+                *ms += delta_ms;
+            }
+            let due = alarm
+                .since_notification_ms
+                .is_none_or(|ms| ms >= rule.notify_interval_ms);
+            if alarm.active && !alarm.acknowledged && due {
+                if let Some(message) = &alarm.message {
+                    notify(
+                        &config.command,
+                        format!("Alarm {}: {}", alarm.name, message),
+                    );
+                }
+                alarm.notifications += 1;
+                alarm.last_notification = Some(Utc::now());
+                alarm.since_notification_ms = Some(0.0);
+            }
+        }
+        changed
+    }
+}
+
+// Reason the condition of `rule` holds, None when it does not
+fn condition(rule: &AlarmRule, status: &FridgeStatus, restarted: bool) -> Option<String> {
+    match rule {
+        AlarmRule::Bounds { sensor, min, max } => {
+            let (name, temperature) = match sensor {
+                Some(sensor) => (sensor.as_str(), *status.temperatures.get(sensor)?),
+                None => ("inside", status.inside_temp),
+            };
+            // A stale temperature is the last one read, the SensorStale rule covers it
+            if status.stale_sensors.iter().any(|s| s == name)
+                || (sensor.is_none() && status.safe_state)
+            {
+                return None;
+            }
+            if let Some(min) = min.filter(|min| temperature < *min) {
+                return Some(format!(
+                    "{} temperature {:.1} °C is below {:.1} °C",
+                    name, temperature, min
+                ));
+            }
+            max.filter(|max| temperature > *max).map(|max| {
+                format!(
+                    "{} temperature {:.1} °C is above {:.1} °C",
+                    name, temperature, max
+                )
+            })
+        }
+        AlarmRule::Deviation { max_deviation } => {
+            if status.operation_mode == OperationMode::Off || status.safe_state {
+                return None;
+            }
+            let deviation = status.inside_temp - status.target_temp;
+            if deviation.abs() > *max_deviation {
+                Some(format!(
+                    "inside temperature {:.1} °C is {:.1} °C from the target {:.1} °C",
+                    status.inside_temp,
+                    deviation.abs(),
+                    status.target_temp
+                ))
+            } else {
+                None
+            }
+        }
+        AlarmRule::SensorStale { sensor } => {
+            let stale: Vec<&String> = status
+                .stale_sensors
+                .iter()
+                .filter(|name| sensor.as_ref().is_none_or(|sensor| sensor == *name))
+                .collect();
+            if stale.is_empty() {
+                None
+            } else {
+                Some(format!(
+                    "sensor {} is not read",
+                    stale
+                        .iter()
+                        .map(|name| name.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))
+            }
+        }
+        AlarmRule::Fault => {
+            if status.faults.is_empty() {
+                None
+            } else {
+                Some(
+                    status
+                        .faults
+                        .iter()
+                        .map(|fault| fault.description())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                )
+            }
+        }
+        AlarmRule::DoorLeftOpen => status
+            .door
+            .filter(|door| door.left_open)
+            .map(|door| format!("door is open for {:.0} min", door.open_ms / 60000.0)),
+        AlarmRule::Restart => {
+            if restarted {
+                Some("controller restarted after it stopped without shutting down".to_string())
+            } else {
+                None
+            }
+        }
+    }
+}
+
+// Run the notification command on its own thread, the control loop does not wait for it
+fn notify(command: &Option<Vec<String>>, message: String) {
+    let command = match command {
+        Some(command) => command.clone(),
+        None => return,
+    };
+    let spawned = thread::Builder::new()
+        .name("alarm notification".to_string())
+        .spawn(move || {
+            match Command::new(&command[0])
+                .args(&command[1..])
+                .arg(&message)
+                .status()
+            {
+                Ok(status) if status.success() => info!("Sent notification: {}", message),
+                Ok(status) => error!("Notification command failed with {}", status),
+                Err(e) => error!("Could not run the notification command: {:?}", e),
+            }
+        });
+    if let Err(e) = spawned {
+        error!("Could not send notification: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+    use crate::controller::DoorStatus;
+
+    const TICK_MS: f64 = 1000.0;
+
+    fn config(rule: AlarmRule, debounce_ms: f64) -> AlarmsConfig {
+        AlarmsConfig {
+            rules: vec![AlarmConfig::new("alarm", rule, debounce_ms)],
+            command: None,
+        }
+    }
+
+    fn status(inside_temp: f64) -> FridgeStatus {
+        FridgeStatus {
+            inside_temp,
+            target_temp: 18.0,
+            operation_mode: OperationMode::Cooling,
+            ..FridgeStatus::default()
+        }
+    }
+
+    fn too_warm() -> AlarmRule {
+        AlarmRule::Bounds {
+            sensor: None,
+            min: None,
+            max: Some(20.0),
+        }
+    }
+
+    #[test]
+    fn raises_after_debounce_and_clears() {
+        let config = config(too_warm(), 60000.0);
+        let mut alarms = Alarms::new(false);
+        for _ in 0..59 {
+            assert!(!alarms.update(&status(25.0), &config, TICK_MS));
+        }
+        assert!(alarms.active().is_empty());
+        assert!(alarms.update(&status(25.0), &config, TICK_MS));
+        assert_eq!(alarms.active(), vec!["alarm".to_string()]);
+
+        // A single good reading clears it and restarts the debounce
+        assert!(alarms.update(&status(19.0), &config, TICK_MS));
+        assert!(alarms.active().is_empty());
+        assert!(!alarms.update(&status(25.0), &config, TICK_MS));
+        assert_eq!(alarms.alarms()[0].condition_ms, TICK_MS);
+    }
+
+    #[test]
+    fn notifies_once_per_interval() {
+        let config = config(too_warm(), 0.0);
+        let mut alarms = Alarms::new(false);
+        // At the start and after five minutes
+        for _ in 0..500 {
+            alarms.update(&status(25.0), &config, TICK_MS);
+        }
+        assert_eq!(alarms.alarms()[0].notifications, 2);
+
+        // Clearing and raising again within the interval does not notify again
+        alarms.update(&status(19.0), &config, TICK_MS);
+        alarms.update(&status(25.0), &config, TICK_MS);
+        assert_eq!(alarms.alarms()[0].notifications, 2);
+    }
+
+    #[test]
+    fn acknowledge_stops_notifications_until_raised_again() {
+        let config = config(too_warm(), 0.0);
+        let mut alarms = Alarms::new(false);
+        alarms.update(&status(19.0), &config, TICK_MS);
+        assert!(!alarms.get_mut("alarm").unwrap().acknowledge());
+        alarms.update(&status(25.0), &config, TICK_MS);
+        assert!(alarms.get_mut("alarm").unwrap().acknowledge());
+        for _ in 0..600 {
+            alarms.update(&status(25.0), &config, TICK_MS);
+        }
+        assert_eq!(alarms.alarms()[0].notifications, 1);
+        assert_eq!(alarms.active(), vec!["alarm".to_string()]);
+
+        // Raised again after it cleared, the interval has passed
+        alarms.update(&status(19.0), &config, TICK_MS);
+        assert!(!alarms.alarms()[0].acknowledged);
+        alarms.update(&status(25.0), &config, TICK_MS);
+        assert_eq!(alarms.alarms()[0].notifications, 2);
+    }
+
+    #[test]
+    fn door_left_open_is_a_default_alarm() {
+        let config = AlarmsConfig::default();
+        let mut alarms = Alarms::new(false);
+        let mut open = FridgeStatus {
+            door: Some(DoorStatus {
+                open: true,
+                open_ms: 60000.0,
+                left_open: false,
+            }),
+            ..status(18.0)
+        };
+        assert!(!alarms.update(&open, &config, TICK_MS));
+        open.door = Some(DoorStatus {
+            open: true,
+            open_ms: 300000.0,
+            left_open: true,
+        });
+        assert!(alarms.update(&open, &config, TICK_MS));
+        assert_eq!(alarms.active(), vec!["door_left_open".to_string()]);
+        let message = alarms.alarms()[3].message.clone();
+        assert_eq!(message.as_deref(), Some("door is open for 5 min"));
+    }
+
+    #[test]
+    fn restart_is_detected_by_the_running_file() {
+        let path = env::temp_dir().join(format!("frust-running-{}", process::id()));
+        let _ = fs::remove_file(&path);
+        assert!(!mark_running(&path).unwrap());
+        // Not removed, the run did not shut down
+        let restarted = mark_running(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(restarted);
+
+        let config = AlarmsConfig::default();
+        let mut alarms = Alarms::new(restarted);
+        assert!(alarms.update(&status(18.0), &config, TICK_MS));
+        assert_eq!(alarms.active(), vec!["restart".to_string()]);
+        // It only clears when acknowledged
+        for _ in 0..600 {
+            alarms.update(&status(18.0), &config, TICK_MS);
+        }
+        assert!(alarms.get_mut("restart").unwrap().acknowledge());
+        assert!(alarms.update(&status(18.0), &config, TICK_MS));
+        assert!(alarms.active().is_empty());
+    }
+}
@@ -0,0 +1,334 @@
+//! PID autotuning with a relay experiment (Åström–Hägglund).
+//! The compressor or heater is switched fully on and off around the setpoint,
+//! which makes the inside temperature oscillate. The amplitude and period of
+//! the oscillation give the ultimate gain and period, from which the gains
+//! are computed with the Ziegler–Nichols and Tyreus–Luyben rules.
+use std::{collections::BTreeMap, f64::consts::PI};
+
+use anyhow::{bail, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::controller::OperationMode;
+use crate::pid::Gains;
+
+// The relay switches the correction between 0 and 100, so its amplitude is 50
+const RELAY_AMPLITUDE: f64 = 50.0;
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutotuneSettings {
+    // Temperature to oscillate around, the current target temperature when left out
+    pub setpoint: Option<f64>,
+
+    // Distance from the setpoint before the relay switches (°C)
+    pub hysteresis: f64,
+
+    // Number of oscillations to measure, the first one is not counted
+    pub cycles: usize,
+
+    // Give up when the oscillations were not measured within this time
+    pub max_duration_ms: f64,
+}
+
+impl Default for AutotuneSettings {
+    fn default() -> AutotuneSettings {
+        AutotuneSettings {
+            setpoint: None,
+            hysteresis: 0.2,
+            cycles: 3,
+            // A week, an oscillation of a full fermenter can take a day
+            max_duration_ms: 604800000.0,
+        }
+    }
+}
+
+impl AutotuneSettings {
+    pub fn validate(&self) -> Result<()> {
+        if let Some(setpoint) = self.setpoint {
+            if !setpoint.is_finite() {
+                bail!("setpoint must be a number");
+            }
+        }
+        if !self.hysteresis.is_finite() || self.hysteresis <= 0.0 {
+            bail!("hysteresis must be larger than 0");
+        }
+        if self.cycles == 0 {
+            bail!("at least one cycle must be measured");
+        }
+        if !self.max_duration_ms.is_finite() || self.max_duration_ms <= 0.0 {
+            bail!("max_duration_ms must be larger than 0");
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TuningRule {
+    ZieglerNichols,
+    TyreusLuyben,
+    TyreusLuybenPi,
+}
+
+impl TuningRule {
+    // Proportional gain as a fraction of the ultimate gain,
+    // integral and derivative time as a fraction of the ultimate period
+    fn factors(&self) -> (f64, f64, Option<f64>) {
+        match self {
+            TuningRule::ZieglerNichols => (0.6, 0.5, Some(0.125)),
+            TuningRule::TyreusLuyben => (1.0 / 2.2, 2.2, Some(1.0 / 6.3)),
+            TuningRule::TyreusLuybenPi => (1.0 / 3.2, 2.2, None),
+        }
+    }
+
+    /// Gains for the ultimate gain and period (control ticks)
+    pub fn gains(&self, ultimate_gain: f64, period_ticks: f64) -> Gains {
+        let (p_factor, integral_factor, derivative_factor) = self.factors();
+        let p = p_factor * ultimate_gain;
+        // The PID controller has no notion of time, its gains are per tick
+        Gains {
+            p,
+            i: p / (integral_factor * period_ticks),
+            d: derivative_factor.map_or(0.0, |factor| p * factor * period_ticks),
+        }
+    }
+}
+
+/// One oscillation of the inside temperature
+#[derive(Debug, Copy, Clone, Serialize)]
+pub struct Cycle {
+    pub period_ms: f64,
+    // A tick reads the sensors too, so it takes longer than the control interval
+    pub period_ticks: u64,
+    pub amplitude: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AutotuneResult {
+    pub ultimate_gain: f64,
+    pub ultimate_period_ms: f64,
+    pub ultimate_period_ticks: f64,
+    pub amplitude: f64,
+    pub gains: BTreeMap<TuningRule, Gains>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "state")]
+pub enum AutotuneState {
+    Running,
+    Done { result: AutotuneResult },
+    Failed { reason: String },
+    Aborted,
+}
+
+/// A relay experiment and its measurements
+#[derive(Debug, Clone, Serialize)]
+pub struct Autotune {
+    pub settings: AutotuneSettings,
+    pub setpoint: f64,
+
+    // Cooling or Heating, the relay switches the compressor or the heater
+    pub operation_mode: OperationMode,
+
+    #[serde(flatten)]
+    pub state: AutotuneState,
+
+    pub elapsed_ms: f64,
+
+    // Control ticks with an inside temperature, the ones that update the PID controller
+    pub ticks: u64,
+
+    // Compressor or heater is asked to be on
+    pub relay: bool,
+
+    // Measured oscillations, the first one is left out of the result
+    pub cycles: Vec<Cycle>,
+
+    // Time the relay last turned on, the start of the current cycle
+    #[serde(skip)]
+    cycle_start_ms: Option<f64>,
+    #[serde(skip)]
+    cycle_start_tick: u64,
+
+    // Lowest and highest inside temperature in the current cycle
+    #[serde(skip)]
+    cycle_min: f64,
+    #[serde(skip)]
+    cycle_max: f64,
+}
+
+impl Autotune {
+    pub fn start(
+        settings: AutotuneSettings,
+        setpoint: f64,
+        operation_mode: OperationMode,
+    ) -> Result<Autotune> {
+        settings.validate()?;
+        if operation_mode != OperationMode::Cooling && operation_mode != OperationMode::Heating {
+            bail!("autotuning needs operation mode Cooling or Heating");
+        }
+        info!(
+            "Starting autotune around {} in operation mode {:?}",
+            setpoint, operation_mode
+        );
+        Ok(Autotune {
+            settings,
+            setpoint,
+            operation_mode,
+            state: AutotuneState::Running,
+            elapsed_ms: 0.0,
+            ticks: 0,
+            relay: false,
+            cycles: Vec::new(),
+            cycle_start_ms: None,
+            cycle_start_tick: 0,
+            cycle_min: f64::INFINITY,
+            cycle_max: f64::NEG_INFINITY,
+        })
+    }
+
+    pub fn is_running(&self) -> bool {
+        matches!(self.state, AutotuneState::Running)
+    }
+
+    pub fn abort(&mut self) {
+        if self.is_running() {
+            info!("Autotune aborted");
+            self.state = AutotuneState::Aborted;
+        }
+    }
+
+    /// Advance the experiment by `delta_ms`, returns whether the relay should be on
+    pub fn update(&mut self, inside_temp: Option<f64>, delta_ms: f64) -> bool {
+        if !self.is_running() {
+            return false;
+        }
+        self.elapsed_ms += delta_ms;
+        if self.elapsed_ms > self.settings.max_duration_ms {
+            self.fail(format!(
+                "measured {} of {} cycles within {} ms",
+                self.cycles.len().saturating_sub(1),
+                self.settings.cycles,
+                self.settings.max_duration_ms
+            ));
+            return false;
+        }
+        let inside_temp = match inside_temp {
+            Some(inside_temp) => inside_temp,
+            None => return self.relay,
+        };
+        self.ticks += 1;
+        self.cycle_min = self.cycle_min.min(inside_temp);
+        self.cycle_max = self.cycle_max.max(inside_temp);
+
+        // Distance from the setpoint in the direction the relay pushes against
+        let error = match self.operation_mode {
+            OperationMode::Heating => self.setpoint - inside_temp,
+            _ => inside_temp - self.setpoint,
+        };
+        if !self.relay && error > self.settings.hysteresis {
+            self.relay = true;
+            self.start_cycle();
+        } else if self.relay && error < -self.settings.hysteresis {
+            self.relay = false;
+        }
+        self.relay
+    }
+
+    // The relay turned on, which ends the previous cycle
+    fn start_cycle(&mut self) {
+        if let Some(cycle_start_ms) = self.cycle_start_ms {
+            let cycle = Cycle {
+                period_ms: self.elapsed_ms - cycle_start_ms,
+                period_ticks: self.ticks - self.cycle_start_tick,
+                amplitude: (self.cycle_max - self.cycle_min) / 2.0,
+            };
+            info!("Autotune measured {:?}", cycle);
+            self.cycles.push(cycle);
+        }
+        self.cycle_start_ms = Some(self.elapsed_ms);
+        self.cycle_start_tick = self.ticks;
+        self.cycle_min = f64::INFINITY;
+        self.cycle_max = f64::NEG_INFINITY;
+        if self.cycles.len() > self.settings.cycles {
+            self.finish();
+        }
+    }
+
+    fn finish(&mut self) {
+        // The first cycle starts from wherever the temperature was
+        let measured = &self.cycles[1..];
+        let count = measured.len() as f64;
+        let amplitude = measured.iter().map(|cycle| cycle.amplitude).sum::<f64>() / count;
+        let ultimate_period_ms = measured.iter().map(|cycle| cycle.period_ms).sum::<f64>() / count;
+        let ultimate_period_ticks = measured
+            .iter()
+            .map(|cycle| cycle.period_ticks as f64)
+            .sum::<f64>()
+            / count;
+        let hysteresis = self.settings.hysteresis;
+        if amplitude <= hysteresis {
+            self.fail(format!(
+                "amplitude {} is not larger than the hysteresis {}",
+                amplitude, hysteresis
+            ));
+            return;
+        }
+        let ultimate_gain =
+            4.0 * RELAY_AMPLITUDE / (PI * (amplitude.powi(2) - hysteresis.powi(2)).sqrt());
+        let gains = [
+            TuningRule::ZieglerNichols,
+            TuningRule::TyreusLuyben,
+            TuningRule::TyreusLuybenPi,
+        ]
+        .iter()
+        .map(|rule| (*rule, rule.gains(ultimate_gain, ultimate_period_ticks)))
+        .collect();
+        let result = AutotuneResult {
+            ultimate_gain,
+            ultimate_period_ms,
+            ultimate_period_ticks,
+            amplitude,
+            gains,
+        };
+        info!("Autotune done {:?}", result);
+        self.relay = false;
+        self.state = AutotuneState::Done { result };
+    }
+
+    fn fail(&mut self, reason: String) {
+        warn!("Autotune failed: {}", reason);
+        self.relay = false;
+        self.state = AutotuneState::Failed { reason };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sensor reads make a tick take longer than the control interval
+    const TICK_MS: f64 = 2500.0;
+
+    #[test]
+    fn counts_the_period_in_ticks() {
+        let mut autotune =
+            Autotune::start(AutotuneSettings::default(), 18.0, OperationMode::Cooling).unwrap();
+        // Inside temperature in hundredths of a degree, the compressor cools as fast as it warms
+        let mut temp = 1830;
+        while autotune.is_running() {
+            let relay = autotune.update(Some(temp as f64 / 100.0), TICK_MS);
+            temp += if relay { -1 } else { 1 };
+        }
+        let result = match &autotune.state {
+            AutotuneState::Done { result } => result.clone(),
+            state => panic!("autotune did not finish: {:?}", state),
+        };
+        // From 18.21 down to 17.79 and back
+        assert_eq!(result.ultimate_period_ticks, 84.0);
+        assert_eq!(result.ultimate_period_ms, 84.0 * TICK_MS);
+        let gains = result.gains[&TuningRule::TyreusLuyben];
+        assert_eq!(gains.i, gains.p / (2.2 * 84.0));
+        assert_eq!(gains.d, gains.p / 6.3 * 84.0);
+    }
+}
@@ -0,0 +1,63 @@
+//! Fan out controller status messages to Server-Sent Events subscribers.
+//! The control loop sends `FridgeStatusMessage`s to the `Broadcaster` actor
+//! which never blocks the sender. Subscribers that can't keep up are dropped.
+use actix::{Actor, Context, Handler, Message, MessageResult};
+use actix_web::web::Bytes;
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use log::{error, info};
+
+use crate::FridgeStatusMessage;
+
+// Number of messages buffered per subscriber before it is considered too slow
+const SUBSCRIBER_BUFFER_SIZE: usize = 16;
+
+#[derive(Default)]
+pub struct Broadcaster {
+    subscribers: Vec<Sender<Bytes>>,
+}
+
+impl Actor for Broadcaster {
+    type Context = Context<Self>;
+}
+
+/// Register a new subscriber, returns the stream of encoded events
+#[derive(Message)]
+#[rtype(result = "Receiver<Bytes>")]
+pub struct Subscribe;
+
+impl Handler<Subscribe> for Broadcaster {
+    type Result = MessageResult<Subscribe>;
+
+    fn handle(&mut self, _: Subscribe, _: &mut Context<Self>) -> Self::Result {
+        let (tx, rx) = channel(SUBSCRIBER_BUFFER_SIZE);
+        self.subscribers.push(tx);
+        info!("New subscriber, {} in total", self.subscribers.len());
+        MessageResult(rx)
+    }
+}
+
+impl Handler<FridgeStatusMessage> for Broadcaster {
+    type Result = ();
+
+    fn handle(&mut self, msg: FridgeStatusMessage, _: &mut Context<Self>) {
+        let data = match serde_json::to_string(&msg) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Could not encode status message: {}", e);
+                return;
+            }
+        };
+        let event = Bytes::from(format!("event: {}\ndata: {}\n\n", msg.event.name(), data));
+        let before = self.subscribers.len();
+        // Drop disconnected subscribers and subscribers with a full buffer
+        self.subscribers
+            .retain_mut(|tx| tx.try_send(event.clone()).is_ok());
+        if self.subscribers.len() < before {
+            info!(
+                "Dropped {} subscriber(s), {} remaining",
+                before - self.subscribers.len(),
+                self.subscribers.len()
+            );
+        }
+    }
+}
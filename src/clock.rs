@@ -0,0 +1,26 @@
+//! Source of time for the control loop and everything that keeps time with it,
+//! so a simulation can run faster than real time.
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Source of time for the control loop
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn sleep(&self, duration: Duration);
+}
+
+/// Wall clock time
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
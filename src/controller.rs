@@ -0,0 +1,1720 @@
+//! Heating/cooling state machine of the fridge.
+//! The `Controller` only decides what the relays should do given the sensor
+//! readings and the elapsed time. Reading sensors, driving the relays and
+//! keeping time is done through the `Actuators` and `Clock` traits.
+use std::{collections::BTreeMap, path::Path, sync::Arc};
+
+use anyhow::{bail, Result};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::clock::Clock;
+use crate::diagnostics::Fault;
+use crate::gpio::{OutputPin, PinConfig, SYSFS_ROOT};
+use crate::pid::{Authority, Gains, Pid, PidLimits, PidTerms};
+use crate::profiles::ProfileStatus;
+use crate::switches::{InputConfig, Switch};
+
+// Current duty cycle
+const MIN_DUTY_CYCLE_MS: f64 = 0.0;
+
+// Longest a manual override can force the relays, a day
+pub const MAXIMUM_OVERRIDE_MS: f64 = 86400000.0;
+
+/// Compressor and heater protection times, all in ms
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Timing {
+    // Wait before switching between heating and cooling mode
+    pub minimum_heating_cooling_switch_time_ms: f64,
+
+    // Wait before switching between cooling and heating mode
+    pub minimum_cooling_heating_switch_time_ms: f64,
+
+    // Wait before turning the compressor on again
+    pub minimum_idle_time_cooling_ms: f64,
+
+    // Wait before turning on the heater again
+    pub minimum_idle_time_heating_ms: f64,
+
+    // Minimum time of cooling before turning it off
+    pub minimum_cool_time_ms: f64,
+
+    // Minimum time of heating before turning it off
+    pub minimum_heat_time_ms: f64,
+
+    // Duty cycle time
+    pub duty_cycle_ms: f64,
+}
+
+impl Default for Timing {
+    fn default() -> Timing {
+        Timing {
+            // Wait an hour before switching between heating and cooling mode
+            minimum_heating_cooling_switch_time_ms: 3600000.0,
+            // Wait an hour before switching between cooling and heating mode
+            minimum_cooling_heating_switch_time_ms: 3600000.0,
+            // Minimum of 90 s before turning the compressor on again
+            minimum_idle_time_cooling_ms: 90000.0,
+            // Wait 10 seconds before turning on the heater again
+            minimum_idle_time_heating_ms: 10000.0,
+            // Minimum of 15 s of cooling before turning it off
+            minimum_cool_time_ms: 15000.0,
+            // Minimum of 30 seconds heating
+            minimum_heat_time_ms: 30000.0,
+            duty_cycle_ms: 300000.0,
+        }
+    }
+}
+
+impl Timing {
+    pub fn validate(&self) -> Result<()> {
+        let times = [
+            (
+                "minimum_heating_cooling_switch_time_ms",
+                self.minimum_heating_cooling_switch_time_ms,
+            ),
+            (
+                "minimum_cooling_heating_switch_time_ms",
+                self.minimum_cooling_heating_switch_time_ms,
+            ),
+            (
+                "minimum_idle_time_cooling_ms",
+                self.minimum_idle_time_cooling_ms,
+            ),
+            (
+                "minimum_idle_time_heating_ms",
+                self.minimum_idle_time_heating_ms,
+            ),
+            ("minimum_cool_time_ms", self.minimum_cool_time_ms),
+            ("minimum_heat_time_ms", self.minimum_heat_time_ms),
+            ("duty_cycle_ms", self.duty_cycle_ms),
+        ];
+        for (name, time) in times.iter() {
+            if !time.is_finite() || *time < 0.0 {
+                bail!("{} must be a positive number of ms, got {}", name, time);
+            }
+        }
+        if self.duty_cycle_ms == 0.0 {
+            bail!("duty_cycle_ms must be larger than 0");
+        }
+        if self.minimum_cool_time_ms > self.duty_cycle_ms {
+            bail!("minimum_cool_time_ms can't be longer than duty_cycle_ms");
+        }
+        if self.minimum_heat_time_ms > self.duty_cycle_ms {
+            bail!("minimum_heat_time_ms can't be longer than duty_cycle_ms");
+        }
+        Ok(())
+    }
+}
+
+/// Gains of the PID controller up to a setpoint
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+pub struct GainSchedule {
+    pub max_setpoint: f64,
+
+    #[serde(flatten)]
+    pub gains: Gains,
+}
+
+/// How the correction is computed from the temperatures
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+pub enum ControlStrategy {
+    // PID controller with the configured gains, the correction sets the duty cycle
+    #[default]
+    Pid,
+    // On/off thermostat, switches at the edges of a band (°C) around the target temperature
+    Hysteresis {
+        band: f64,
+    },
+    // PID controller with the gains of the first schedule whose max_setpoint is not
+    // below the setpoint, or of the last schedule
+    GainScheduled {
+        schedule: Vec<GainSchedule>,
+    },
+}
+
+impl ControlStrategy {
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            ControlStrategy::Pid => {}
+            ControlStrategy::Hysteresis { band } => {
+                if !band.is_finite() || *band <= 0.0 {
+                    bail!("hysteresis band must be larger than 0");
+                }
+            }
+            ControlStrategy::GainScheduled { schedule } => {
+                if schedule.is_empty() {
+                    bail!("gain schedule is empty");
+                }
+                for (i, entry) in schedule.iter().enumerate() {
+                    entry.gains.validate()?;
+                    if !entry.max_setpoint.is_finite() {
+                        bail!("max_setpoint of gain schedule {} must be a number", i);
+                    }
+                    if i > 0 && entry.max_setpoint <= schedule[i - 1].max_setpoint {
+                        bail!("gain schedules must be ordered by max_setpoint");
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Gains of the PID controller at a setpoint, `gains` unless they are scheduled
+    fn gains(&self, gains: Gains, setpoint: f64) -> Gains {
+        match self {
+            ControlStrategy::GainScheduled { schedule } => schedule
+                .iter()
+                .find(|entry| setpoint <= entry.max_setpoint)
+                .or_else(|| schedule.last())
+                .map_or(gains, |entry| entry.gains),
+            _ => gains,
+        }
+    }
+}
+
+/// Cascade control: the PID controller on the inside temperature sets the
+/// setpoint of an inner PID controller on the fridge air temperature
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Cascade {
+    // Gains of the inner loop on the air temperature
+    pub p: f64,
+    pub i: f64,
+    pub d: f64,
+
+    // Maximum distance of the air setpoint from the target temperature (°C)
+    pub max_air_deviation: f64,
+}
+
+impl Default for Cascade {
+    fn default() -> Cascade {
+        Cascade {
+            p: 10.0,
+            i: 0.0,
+            d: 0.0,
+            max_air_deviation: 5.0,
+        }
+    }
+}
+
+impl Cascade {
+    pub fn validate(&self) -> Result<()> {
+        if !(self.p.is_finite() && self.i.is_finite() && self.d.is_finite()) {
+            bail!("cascade gains must be numbers");
+        }
+        if !self.max_air_deviation.is_finite() || self.max_air_deviation <= 0.0 {
+            bail!("max_air_deviation must be larger than 0");
+        }
+        Ok(())
+    }
+}
+
+/// Feed-forward on the difference between the outside and target temperature,
+/// to react to a warm or cold room before the inside temperature drifts
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct FeedForward {
+    pub enabled: bool,
+
+    // Correction per °C the outside temperature is above or below the target
+    pub gain: f64,
+}
+
+impl Default for FeedForward {
+    fn default() -> FeedForward {
+        FeedForward {
+            enabled: false,
+            gain: 2.0,
+        }
+    }
+}
+
+impl FeedForward {
+    pub fn validate(&self) -> Result<()> {
+        if !self.gain.is_finite() {
+            bail!("feed_forward gain must be a number");
+        }
+        Ok(())
+    }
+}
+
+/// Relay states forced for a limited time, for cleaning, defrosting or checking the wiring
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ManualOverride {
+    #[serde(default)]
+    pub compressor: bool,
+    #[serde(default)]
+    pub heater: bool,
+
+    // Time the override lasts
+    pub duration_ms: f64,
+
+    // Turn the compressor on without waiting for its minimum idle time
+    #[serde(default, rename = "unsafe")]
+    pub unprotected: bool,
+
+    // Time left before the controller takes over again
+    #[serde(default, skip_deserializing)]
+    pub remaining_ms: f64,
+}
+
+impl ManualOverride {
+    pub fn validate(&self) -> Result<()> {
+        if !self.duration_ms.is_finite()
+            || self.duration_ms <= 0.0
+            || self.duration_ms > MAXIMUM_OVERRIDE_MS
+        {
+            bail!(
+                "duration_ms must be larger than 0 and at most {}",
+                MAXIMUM_OVERRIDE_MS
+            );
+        }
+        if self.compressor && self.heater {
+            bail!("compressor and heater can't both be on");
+        }
+        Ok(())
+    }
+
+    /// Count down by `delta_ms`, returns whether the override is still active
+    pub fn advance(&mut self, delta_ms: f64) -> bool {
+        self.remaining_ms -= delta_ms;
+        self.remaining_ms > 0.0
+    }
+
+    fn mode(&self) -> Mode {
+        if self.compressor {
+            Mode::Cooling
+        } else if self.heater {
+            Mode::Heating
+        } else {
+            Mode::Idle
+        }
+    }
+}
+
+/// Door switch for a step
+#[derive(Debug, Copy, Clone)]
+pub struct DoorInput {
+    pub open: bool,
+
+    // Time after which an open door is reported as left open
+    pub left_open_ms: f64,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct DoorStatus {
+    pub open: bool,
+
+    // Time the door has been open (ms), 0 while it is closed
+    pub open_ms: f64,
+
+    // Open for longer than the configured time
+    pub left_open: bool,
+}
+
+/// Current sensed through the relays for a step, None for a relay without feedback
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RelayFeedback {
+    pub compressor: Option<bool>,
+    pub heater: Option<bool>,
+
+    // Time the feedback may differ from the relay state before it is a fault
+    pub timeout_ms: f64,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+pub enum RelayFault {
+    // Current flows while the relay is off
+    Stuck,
+    // No current flows while the relay is on
+    Failed,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct FeedbackStatus {
+    // Current flows through the relay
+    pub current: bool,
+
+    // Time the feedback has differed from the relay state (ms)
+    pub mismatch_ms: f64,
+
+    pub fault: Option<RelayFault>,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+pub enum Mode {
+    Idle,
+    Cooling,
+    Heating,
+}
+
+// Mode of operation
+// Either cooling or heating, Auto switches between the two and Off keeps both relays off
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+pub enum OperationMode {
+    Cooling,
+    Heating,
+    Auto,
+    Off,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FridgeStatus {
+    // Temperature in milli degrees
+    pub inside_temp: f64,
+
+    // Outside temp in milli degrees
+    pub outside_temp: f64,
+
+    // Current target temperature, from the configuration or the active profile
+    pub target_temp: f64,
+
+    // Position in the active profile
+    pub profile: Option<ProfileStatus>,
+
+    // Filtered temperature of every configured sensor by name
+    pub temperatures: BTreeMap<String, f64>,
+
+    // Sensors without a successful read within their stale timeout
+    pub stale_sensors: Vec<String>,
+
+    // Both relays are kept off because the inside temperature is unknown
+    pub safe_state: bool,
+
+    // A relay experiment drives the compressor or heater instead of the PID controller
+    pub autotuning: bool,
+
+    // Relay states forced through the API, replaces the controller until it expires
+    pub manual_override: Option<ManualOverride>,
+
+    // Door switch, None without one. The compressor is paused while the door is open.
+    pub door: Option<DoorStatus>,
+
+    // Feedback of the relays with a current sense input, by relay
+    pub relay_feedback: BTreeMap<String, FeedbackStatus>,
+
+    // Faults found by the diagnostics, including the relay feedback faults
+    pub faults: Vec<Fault>,
+
+    // Names of the active alarms, their state is at /api/alarms
+    pub alarms: Vec<String>,
+
+    // Correction from the PID controller
+    pub correction: f64,
+
+    // Part of the correction from the outside temperature, None when feed-forward is off
+    pub feed_forward: Option<f64>,
+
+    // Setpoint of the inner loop from the outer loop, None without cascade control
+    pub air_setpoint: Option<f64>,
+
+    // Terms of the PID controllers by the temperature they control (inside, air)
+    pub pid_terms: BTreeMap<String, PidTerms>,
+
+    // Current operation mode (heating, cooling or off)
+    pub operation_mode: OperationMode,
+
+    // Operation mode from the configuration, Auto switches the current operation mode
+    pub configured_operation_mode: OperationMode,
+
+    // Current mode while operational
+    pub mode: Mode,
+
+    // Amount of time spent in mode (ms)
+    pub mode_ms: f64,
+
+    // Current duty cycle (ms on / total duty cycle)
+    pub duty_cycle: f64,
+
+    // Target duty cycle (ms on)
+    pub target_duty_cycle: f64,
+}
+
+impl Default for FridgeStatus {
+    fn default() -> FridgeStatus {
+        FridgeStatus {
+            inside_temp: 10.0,
+            outside_temp: 10.0,
+            target_temp: 20.0,
+            profile: None,
+            temperatures: BTreeMap::new(),
+            stale_sensors: Vec::new(),
+            safe_state: false,
+            autotuning: false,
+            manual_override: None,
+            door: None,
+            relay_feedback: BTreeMap::new(),
+            faults: Vec::new(),
+            alarms: Vec::new(),
+            correction: 0.0,
+            feed_forward: None,
+            air_setpoint: None,
+            pid_terms: BTreeMap::new(),
+            operation_mode: OperationMode::Off,
+            configured_operation_mode: OperationMode::Off,
+            mode: Mode::Idle,
+            mode_ms: 0.0,
+            duty_cycle: 0.0,
+            target_duty_cycle: 0.0,
+        }
+    }
+}
+
+/// Everything the controller needs to know for a single step
+#[derive(Debug, Clone)]
+pub struct Inputs {
+    // Inside temperature, None when the sensor is stale
+    pub inside_temp: Option<f64>,
+
+    // Outside temperature, None when there is no (fresh) outside sensor
+    pub outside_temp: Option<f64>,
+
+    // Fridge air temperature for cascade control, None when it is stale
+    pub air_temp: Option<f64>,
+
+    // Setpoint of the PID controller
+    pub target_temp: f64,
+
+    // Configured mode of operation
+    pub operation_mode: OperationMode,
+
+    // Relay state asked for by autotuning, replaces the PID controller
+    pub relay: Option<bool>,
+
+    // Forced relay states, replaces both the PID controller and autotuning
+    pub manual_override: Option<ManualOverride>,
+
+    // Door switch, None without one
+    pub door: Option<DoorInput>,
+
+    pub relay_feedback: RelayFeedback,
+
+    pub strategy: ControlStrategy,
+
+    // Configured gains of the PID controller
+    pub gains: Gains,
+
+    // Output and term limits of the PID controllers
+    pub pid_limits: PidLimits,
+
+    // Cascade control on the air temperature, None for a single loop
+    pub cascade: Option<Cascade>,
+
+    pub feed_forward: FeedForward,
+
+    pub timing: Timing,
+}
+
+/// Desired relay states
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Outputs {
+    pub compressor: bool,
+    pub heater: bool,
+}
+
+/// Drives the compressor and heater relays
+pub trait Actuators {
+    fn apply(&mut self, outputs: &Outputs) -> Result<()>;
+}
+
+/// Level at which the relays of a board switch on
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub enum RelayBoard {
+    #[default]
+    ActiveHigh,
+    // Most optocoupler relay modules switch on when the input is pulled low
+    ActiveLow,
+}
+
+/// GPIO pin of a relay
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RelayPin {
+    #[serde(flatten)]
+    pub pin: PinConfig,
+
+    // Polarity of this relay, None follows the board
+    #[serde(default)]
+    pub active_low: Option<bool>,
+
+    // State of the relay from startup until the first control step
+    #[serde(default)]
+    pub initial_on: bool,
+
+    // Current sense input that is on while current flows through the relay
+    #[serde(default)]
+    pub feedback: Option<InputConfig>,
+}
+
+impl RelayPin {
+    fn sysfs(pin: u64) -> RelayPin {
+        RelayPin {
+            pin: PinConfig::Sysfs {
+                pin,
+                root: SYSFS_ROOT.into(),
+            },
+            active_low: None,
+            initial_on: false,
+            feedback: None,
+        }
+    }
+
+    fn open(&self, name: &str, board: RelayBoard, clock: &Arc<dyn Clock>) -> Result<OutputPin> {
+        let active_low = self.active_low.unwrap_or(board == RelayBoard::ActiveLow);
+        self.pin
+            .open_output(name, active_low, self.initial_on as u8, clock)
+    }
+
+    fn watch_feedback(&self, name: &str) -> Result<Option<Switch>> {
+        self.feedback
+            .as_ref()
+            .map(|feedback| {
+                Switch::watch(
+                    &format!("{} feedback", name),
+                    feedback,
+                    [
+                        format!("No current through the {} relay", name),
+                        format!("Current through the {} relay", name),
+                    ],
+                )
+            })
+            .transpose()
+    }
+}
+
+/// GPIO pins of the compressor and heater relays
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RelayConfig {
+    pub board: RelayBoard,
+    pub compressor: RelayPin,
+    pub heater: RelayPin,
+
+    // Report a relay fault when its feedback differs from its state for this long
+    pub feedback_timeout_ms: f64,
+}
+
+impl Default for RelayConfig {
+    fn default() -> RelayConfig {
+        RelayConfig {
+            board: RelayBoard::ActiveHigh,
+            // Installs have these pins exported, so they are not free for the character device
+            compressor: RelayPin::sysfs(23),
+            heater: RelayPin::sysfs(24),
+            feedback_timeout_ms: 10000.0,
+        }
+    }
+}
+
+impl RelayConfig {
+    pub fn validate(&self) -> Result<()> {
+        let fake = matches!(self.compressor.pin, PinConfig::Fake { .. });
+        if !fake && self.compressor.pin == self.heater.pin {
+            bail!("compressor and heater can't share a pin");
+        }
+        if self.compressor.initial_on && self.heater.initial_on {
+            bail!("compressor and heater can't both be on initially");
+        }
+        if !self.feedback_timeout_ms.is_finite() || self.feedback_timeout_ms <= 0.0 {
+            bail!("feedback_timeout_ms must be larger than 0");
+        }
+        Ok(())
+    }
+
+    /// The same relays on fake pins that append their transitions to `log`.
+    /// Without hardware no current is sensed, so the feedback inputs are left out.
+    pub fn faked(&self, log: &Path) -> RelayConfig {
+        let fake = |relay: &RelayPin| RelayPin {
+            pin: PinConfig::Fake {
+                log: Some(log.to_path_buf()),
+            },
+            feedback: None,
+            ..relay.clone()
+        };
+        RelayConfig {
+            board: self.board,
+            compressor: fake(&self.compressor),
+            heater: fake(&self.heater),
+            feedback_timeout_ms: self.feedback_timeout_ms,
+        }
+    }
+
+    /// Open both pins in their initial state, fake pins keep time with `clock`
+    pub fn open(&self, clock: &Arc<dyn Clock>) -> Result<RelayPins> {
+        Ok(RelayPins {
+            compressor: Arc::new(self.compressor.open("compressor", self.board, clock)?),
+            heater: Arc::new(self.heater.open("heater", self.board, clock)?),
+        })
+    }
+
+    /// Watch the feedback inputs of the compressor and heater, if any
+    pub fn watch_feedback(&self) -> Result<(Option<Switch>, Option<Switch>)> {
+        Ok((
+            self.compressor.watch_feedback("compressor")?,
+            self.heater.watch_feedback("heater")?,
+        ))
+    }
+}
+
+/// Compressor and heater relays on GPIO pins, shared with the shutdown handler
+pub struct RelayPins {
+    pub compressor: Arc<OutputPin>,
+    pub heater: Arc<OutputPin>,
+}
+
+impl Actuators for RelayPins {
+    fn apply(&mut self, outputs: &Outputs) -> Result<()> {
+        self.compressor.set_value(outputs.compressor as u8)?;
+        self.heater.set_value(outputs.heater as u8)?;
+        Ok(())
+    }
+}
+
+pub struct Controller {
+    // Gains, limits and setpoints of both loops come from the inputs of every step
+    pid: Pid,
+
+    // Inner loop of cascade control
+    inner_pid: Pid,
+
+    status: FridgeStatus,
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        Controller {
+            pid: Pid::new(0.0, 0.0, 0.0, PidLimits::default(), 0.0),
+            inner_pid: Pid::new(0.0, 0.0, 0.0, PidLimits::default(), 0.0),
+            status: FridgeStatus::default(),
+        }
+    }
+
+    pub fn status(&self) -> &FridgeStatus {
+        &self.status
+    }
+
+    /// Advance the state machine by `delta_ms` and return the desired relay states
+    pub fn step(&mut self, inputs: &Inputs, delta_ms: f64) -> Outputs {
+        if let Some(outside_temp) = inputs.outside_temp {
+            self.status.outside_temp = outside_temp;
+        }
+        self.status.target_temp = inputs.target_temp;
+        // Start from idle when an override ends, the relays are in an arbitrary state
+        if inputs.manual_override.is_none()
+            && self.status.manual_override.is_some()
+            && self.status.mode != Mode::Idle
+        {
+            set_mode(&mut self.status, Mode::Idle);
+        }
+        self.status.manual_override = inputs.manual_override;
+        // Feedback follows the relay states of the previous step, which are applied now
+        self.update_relay_feedback(&inputs.relay_feedback, delta_ms);
+        self.update_door(inputs.door, delta_ms);
+        match inputs.inside_temp {
+            Some(inside_temp) => {
+                self.status.inside_temp = inside_temp;
+                if self.status.safe_state {
+                    info!("Inside sensor recovered, resuming control");
+                    self.status.safe_state = false;
+                }
+                self.status.autotuning = inputs.relay.is_some();
+                self.update_pid_settings(inputs);
+                match (inputs.manual_override, inputs.relay) {
+                    (Some(manual_override), _) => {
+                        self.status.correction = 0.0;
+                        self.status.target_duty_cycle = 0.0;
+                        self.track_correction(inputs);
+                        self.update_override(&manual_override, &inputs.timing, delta_ms);
+                    }
+                    (None, Some(relay)) => {
+                        self.status.correction = 0.0;
+                        self.status.target_duty_cycle = 0.0;
+                        self.track_correction(inputs);
+                        self.update_operation_mode(inputs.operation_mode);
+                        self.update_relay(relay, &inputs.timing, delta_ms);
+                    }
+                    (None, None) => {
+                        match &inputs.strategy {
+                            ControlStrategy::Hysteresis { band } => {
+                                self.update_hysteresis(*band, &inputs.timing);
+                                self.track_correction(inputs);
+                            }
+                            ControlStrategy::Pid | ControlStrategy::GainScheduled { .. } => {
+                                self.update_correction(inputs)
+                            }
+                        }
+                        self.update_operation_mode(inputs.operation_mode);
+                        let auto = inputs.operation_mode == OperationMode::Auto;
+                        self.update_mode(auto, &inputs.timing, delta_ms);
+                    }
+                }
+            }
+            // Forcing the relays does not need the inside temperature
+            None => match inputs.manual_override {
+                Some(manual_override) => {
+                    self.update_override(&manual_override, &inputs.timing, delta_ms)
+                }
+                None => self.enter_safe_state(delta_ms),
+            },
+        }
+        self.status.mode_ms += delta_ms;
+        self.outputs()
+    }
+
+    /// Relay states for the current mode
+    pub fn outputs(&self) -> Outputs {
+        Outputs {
+            compressor: self.status.mode == Mode::Cooling,
+            heater: self.status.mode == Mode::Heating,
+        }
+    }
+
+    // Gains, limits and setpoint of the PID controllers for this step.
+    // Gain changes are bumpless, so the strategy and schedule can change at any time.
+    fn update_pid_settings(&mut self, inputs: &Inputs) {
+        let gains = inputs.strategy.gains(inputs.gains, self.status.target_temp);
+        self.pid.set_gains(gains.p, gains.i, gains.d);
+        self.pid.setpoint = self.status.target_temp;
+        // With cascade control the output is the deviation of the air setpoint
+        self.pid.limits = match inputs.cascade {
+            Some(cascade) => PidLimits {
+                output_min: -cascade.max_air_deviation,
+                output_max: cascade.max_air_deviation,
+                i: inputs.pid_limits.i.min(cascade.max_air_deviation),
+                ..inputs.pid_limits
+            },
+            None => inputs.pid_limits,
+        };
+        if let Some(cascade) = inputs.cascade {
+            self.inner_pid.set_gains(cascade.p, cascade.i, cascade.d);
+        }
+        self.inner_pid.limits = inputs.pid_limits;
+    }
+
+    fn update_correction(&mut self, inputs: &Inputs) {
+        let authority = self.authority(inputs);
+        let outer = self
+            .pid
+            .next_control_output(self.status.inside_temp, authority);
+        self.status.pid_terms.clear();
+        self.status.pid_terms.insert("inside".to_string(), outer);
+        let correction = match inputs.cascade {
+            Some(_) => self.update_inner_correction(inputs, authority, outer.output),
+            None => {
+                self.status.air_setpoint = None;
+                self.inner_pid.reset_integral_term();
+                outer.output
+            }
+        };
+
+        // A warmer room asks for cooling, which is a negative correction
+        self.status.feed_forward = match (inputs.feed_forward.enabled, inputs.outside_temp) {
+            (true, Some(outside_temp)) => {
+                Some(inputs.feed_forward.gain * (self.status.target_temp - outside_temp))
+            }
+            _ => None,
+        };
+        self.status.correction = (correction + self.status.feed_forward.unwrap_or(0.0))
+            .clamp(inputs.pid_limits.output_min, inputs.pid_limits.output_max);
+        self.status.target_duty_cycle =
+            (self.status.correction / 100.0).abs() * inputs.timing.duty_cycle_ms;
+    }
+
+    // The output of the outer loop moves the air setpoint away from the target temperature.
+    // Without an air temperature the inner loop controls the inside temperature instead.
+    fn update_inner_correction(
+        &mut self,
+        inputs: &Inputs,
+        authority: Authority,
+        outer_output: f64,
+    ) -> f64 {
+        let pid = &mut self.inner_pid;
+        let status = &mut self.status;
+        let air_temp = inputs.air_temp;
+        let (setpoint, measurement) = match air_temp {
+            Some(air_temp) => (status.target_temp + outer_output, air_temp),
+            None => (status.target_temp, status.inside_temp),
+        };
+        if air_temp.is_none() && status.air_setpoint.is_some() {
+            warn!("No air temperature, the inner loop controls the inside temperature");
+        }
+        status.air_setpoint = air_temp.map(|_| setpoint);
+        pid.setpoint = setpoint;
+        let inner = pid.next_control_output(measurement, authority);
+        status.pid_terms.insert("air".to_string(), inner);
+        inner.output
+    }
+
+    // On/off thermostat: full correction outside the band, inside the band the
+    // compressor or heater keeps running until the temperature reaches the other edge
+    fn update_hysteresis(&mut self, band: f64, timing: &Timing) {
+        let status = &mut self.status;
+        let half_band = band / 2.0;
+        status.correction = if status.inside_temp > status.target_temp + half_band {
+            -100.0
+        } else if status.inside_temp < status.target_temp - half_band {
+            100.0
+        } else {
+            match status.mode {
+                Mode::Cooling => -100.0,
+                Mode::Heating => 100.0,
+                Mode::Idle => 0.0,
+            }
+        };
+        status.target_duty_cycle = (status.correction / 100.0).abs() * timing.duty_cycle_ms;
+    }
+
+    // While the PID controllers are not in control they follow the correction,
+    // so switching back to them is bumpless
+    fn track_correction(&mut self, inputs: &Inputs) {
+        let status = &mut self.status;
+        status.feed_forward = None;
+        status.air_setpoint = None;
+        status.pid_terms.clear();
+        match inputs.cascade {
+            Some(_) => {
+                // The air setpoint starts at the target temperature
+                self.pid.track(0.0, status.inside_temp);
+                self.inner_pid.setpoint = status.target_temp;
+                self.inner_pid.track(
+                    status.correction,
+                    inputs.air_temp.unwrap_or(status.inside_temp),
+                );
+            }
+            None => self.pid.track(status.correction, status.inside_temp),
+        }
+    }
+
+    // Directions the relays can act in right now, a negative correction asks for cooling.
+    // The compressor or heater can only turn on after its minimum idle time and,
+    // in Auto, the other operation mode only after the switch time.
+    fn authority(&self, inputs: &Inputs) -> Authority {
+        let status = &self.status;
+        let timing = &inputs.timing;
+        let auto = inputs.operation_mode == OperationMode::Auto;
+        let can_act = |mode: Mode, operation_mode: OperationMode, idle_ms: f64, switch_ms: f64| {
+            status.mode == mode
+                || (status.mode == Mode::Idle
+                    && if status.operation_mode == operation_mode {
+                        status.mode_ms >= idle_ms
+                    } else {
+                        auto && status.mode_ms > switch_ms
+                    })
+        };
+        Authority {
+            decrease: !door_open(status)
+                && can_act(
+                    Mode::Cooling,
+                    OperationMode::Cooling,
+                    timing.minimum_idle_time_cooling_ms,
+                    timing.minimum_heating_cooling_switch_time_ms,
+                ),
+            increase: can_act(
+                Mode::Heating,
+                OperationMode::Heating,
+                timing.minimum_idle_time_heating_ms,
+                timing.minimum_cooling_heating_switch_time_ms,
+            ),
+        }
+    }
+
+    // Decide between heating and cooling before running the state machine.
+    // Manual modes are followed directly, Auto picks a mode based on the
+    // correction and only switches after a long idle period.
+    fn update_operation_mode(&mut self, operation_mode: OperationMode) {
+        let status = &mut self.status;
+        status.configured_operation_mode = operation_mode;
+        let active = match operation_mode {
+            OperationMode::Auto => match status.operation_mode {
+                // Start in the mode the correction asks for
+                OperationMode::Off | OperationMode::Auto => {
+                    if status.correction < 0.0 {
+                        OperationMode::Cooling
+                    } else {
+                        OperationMode::Heating
+                    }
+                }
+                active => active,
+            },
+            manual => manual,
+        };
+        if active != status.operation_mode {
+            info!("Operation mode set to {:?}", active);
+            status.operation_mode = active;
+            if status.mode != Mode::Idle {
+                set_mode(status, Mode::Idle);
+            }
+        }
+    }
+
+    // Basically, it works by having two operation modes cooling and heating.
+    // In Auto you can only switch between the two if a long period has passed
+    // to prevent any oscillation.
+    //
+    //           correction < 0, idle long enough
+    //   +------+ ----------------------------------> +---------+
+    //   | Idle |                                     | Cooling |
+    //   +------+ <---------------------------------- +---------+
+    //      |      duty cycle reached, cooled long enough
+    //      |
+    //      | Auto only: correction > 0 after idling longer than the switch time
+    //      v
+    //   operation mode Heating, the same with the heater and signs flipped
+    fn update_mode(&mut self, auto: bool, timing: &Timing, delta_ms: f64) {
+        let status = &mut self.status;
+        match status.mode {
+            Mode::Idle => {
+                // Update duty cycle
+                status.duty_cycle = MIN_DUTY_CYCLE_MS.max(status.duty_cycle - delta_ms);
+
+                // The 2 options are
+                // Idle -> Idle
+                // Idle -> Cooling or Heating
+                match status.operation_mode {
+                    OperationMode::Cooling => {
+                        if status.correction < 0.0 {
+                            // Check if we need to turn the cooler on
+                            if status.duty_cycle < status.target_duty_cycle
+                                && status.mode_ms >= timing.minimum_idle_time_cooling_ms
+                                && !door_open(status)
+                            {
+                                info!("Enabling compressor!");
+                                set_mode(status, Mode::Cooling);
+                            }
+                            // We have cooled enough
+                        } else if auto
+                            && status.correction > 0.0
+                            && status.mode_ms > timing.minimum_cooling_heating_switch_time_ms
+                        {
+                            info!("Switching to operation mode heating!");
+                            status.operation_mode = OperationMode::Heating;
+                            set_mode(status, Mode::Idle);
+                        }
+                    }
+                    OperationMode::Heating => {
+                        if status.correction > 0.0 {
+                            // Check if we need to turn the heater on
+                            if status.duty_cycle < status.target_duty_cycle
+                                && status.mode_ms >= timing.minimum_idle_time_heating_ms
+                            {
+                                info!("Enabling heater!");
+                                set_mode(status, Mode::Heating);
+                            }
+                        } else if auto
+                            && status.correction < 0.0
+                            && status.mode_ms > timing.minimum_heating_cooling_switch_time_ms
+                        {
+                            info!("Switching to operation mode cooling!");
+                            status.operation_mode = OperationMode::Cooling;
+                            set_mode(status, Mode::Idle);
+                        }
+                    }
+                    // Stay idle
+                    OperationMode::Off | OperationMode::Auto => {}
+                }
+            }
+            Mode::Cooling => {
+                // Update duty cycle
+                status.duty_cycle = timing.duty_cycle_ms.min(status.duty_cycle + delta_ms);
+
+                // The 2 options are
+                // Cooling -> Idle
+                // Cooling -> Cooling
+
+                if door_open(status) {
+                    info!("Door open, pausing compressor");
+                    set_mode(status, Mode::Idle);
+                } else if status.mode_ms < timing.minimum_cool_time_ms {
+                    // Do nothing because we keep cooling
+                } else if status.duty_cycle > status.target_duty_cycle || status.correction >= 0.0 {
+                    // The target duty cycle is the size of the correction, so a correction
+                    // that asks for heating must not keep the compressor running
+                    info!("Disabling compressor");
+                    set_mode(status, Mode::Idle);
+                }
+            }
+            Mode::Heating => {
+                // Update duty cycle
+                status.duty_cycle = timing.duty_cycle_ms.min(status.duty_cycle + delta_ms);
+
+                // The 2 options are
+                // Heating -> Idle
+                // Heating -> Heating
+
+                if status.mode_ms < timing.minimum_heat_time_ms {
+                    // Do nothing
+                } else if status.duty_cycle > status.target_duty_cycle || status.correction <= 0.0 {
+                    // Same as the compressor, a correction that asks for cooling stops it
+                    info!("Disabling heater");
+                    set_mode(status, Mode::Idle);
+                }
+            }
+        }
+    }
+
+    // Follow the requested relay state of the operation mode,
+    // but only after the minimum idle or on time has passed
+    fn update_relay(&mut self, relay: bool, timing: &Timing, delta_ms: f64) {
+        let status = &mut self.status;
+        let (active, minimum_idle_time_ms, minimum_on_time_ms) = match status.operation_mode {
+            OperationMode::Cooling => (
+                Mode::Cooling,
+                timing.minimum_idle_time_cooling_ms,
+                timing.minimum_cool_time_ms,
+            ),
+            OperationMode::Heating => (
+                Mode::Heating,
+                timing.minimum_idle_time_heating_ms,
+                timing.minimum_heat_time_ms,
+            ),
+            OperationMode::Off | OperationMode::Auto => return,
+        };
+        if status.mode == Mode::Idle {
+            status.duty_cycle = MIN_DUTY_CYCLE_MS.max(status.duty_cycle - delta_ms);
+            let paused = active == Mode::Cooling && door_open(status);
+            if relay && !paused && status.mode_ms >= minimum_idle_time_ms {
+                info!("Relay on for autotuning");
+                set_mode(status, active);
+            }
+        } else {
+            status.duty_cycle = timing.duty_cycle_ms.min(status.duty_cycle + delta_ms);
+            if active == Mode::Cooling && door_open(status) {
+                info!("Door open, pausing compressor");
+                set_mode(status, Mode::Idle);
+            } else if !relay && status.mode_ms >= minimum_on_time_ms {
+                info!("Relay off for autotuning");
+                set_mode(status, Mode::Idle);
+            }
+        }
+    }
+
+    // Follow the relay states of a manual override. The compressor still waits
+    // for its minimum idle time unless the override is unsafe.
+    fn update_override(
+        &mut self,
+        manual_override: &ManualOverride,
+        timing: &Timing,
+        delta_ms: f64,
+    ) {
+        let status = &mut self.status;
+        if status.mode == Mode::Idle {
+            status.duty_cycle = MIN_DUTY_CYCLE_MS.max(status.duty_cycle - delta_ms);
+        } else {
+            status.duty_cycle = timing.duty_cycle_ms.min(status.duty_cycle + delta_ms);
+        }
+        let mode = manual_override.mode();
+        if status.mode == mode {
+            return;
+        }
+        if mode == Mode::Cooling && !manual_override.unprotected {
+            // The heater goes off first, the idle time starts from there
+            if status.mode != Mode::Idle {
+                info!("Override turns off the heater before the compressor");
+                set_mode(status, Mode::Idle);
+                return;
+            }
+            if status.mode_ms < timing.minimum_idle_time_cooling_ms {
+                return;
+            }
+        }
+        info!("Override sets mode {:?}", mode);
+        set_mode(status, mode);
+    }
+
+    // Time the door is open, a door that is left open is reported once
+    fn update_door(&mut self, door: Option<DoorInput>, delta_ms: f64) {
+        self.status.door = door.map(|door| {
+            let open_ms = match (door.open, self.status.door) {
+                (false, _) => 0.0,
+                (true, Some(previous)) => previous.open_ms + delta_ms,
+                (true, None) => 0.0,
+            };
+            let left_open = open_ms >= door.left_open_ms;
+            if left_open && !self.status.door.is_some_and(|previous| previous.left_open) {
+                warn!("Door left open for {} ms", open_ms);
+            }
+            DoorStatus {
+                open: door.open,
+                open_ms,
+                left_open,
+            }
+        });
+    }
+
+    // Compare the sensed current with the relay states. A relay that differs for
+    // longer than the timeout is stuck (current while off) or failed (no current while on).
+    fn update_relay_feedback(&mut self, feedback: &RelayFeedback, delta_ms: f64) {
+        let outputs = self.outputs();
+        for (name, current, on) in [
+            ("compressor", feedback.compressor, outputs.compressor),
+            ("heater", feedback.heater, outputs.heater),
+        ] {
+            let current = match current {
+                Some(current) => current,
+                None => {
+                    self.status.relay_feedback.remove(name);
+                    continue;
+                }
+            };
+            let previous = self.status.relay_feedback.get(name).copied();
+            let mismatch_ms = match (current != on, previous) {
+                (false, _) => 0.0,
+                (true, Some(previous)) => previous.mismatch_ms + delta_ms,
+                (true, None) => 0.0,
+            };
+            let fault = if mismatch_ms >= feedback.timeout_ms {
+                Some(if current {
+                    RelayFault::Stuck
+                } else {
+                    RelayFault::Failed
+                })
+            } else {
+                None
+            };
+            let previous_fault = previous.and_then(|previous| previous.fault);
+            if fault != previous_fault {
+                match fault {
+                    Some(fault) => error!("The {} relay is {:?}", name, fault),
+                    None => info!("The {} relay follows the controller again", name),
+                }
+            }
+            self.status.relay_feedback.insert(
+                name.to_string(),
+                FeedbackStatus {
+                    current,
+                    mismatch_ms,
+                    fault,
+                },
+            );
+        }
+    }
+
+    // Turn off both relays while the inside temperature is unknown
+    fn enter_safe_state(&mut self, delta_ms: f64) {
+        if !self.status.safe_state {
+            error!("Inside sensor is stale, turning off compressor and heater");
+            self.status.safe_state = true;
+        }
+        if self.status.mode != Mode::Idle {
+            set_mode(&mut self.status, Mode::Idle);
+        }
+        self.status.duty_cycle = MIN_DUTY_CYCLE_MS.max(self.status.duty_cycle - delta_ms);
+    }
+}
+
+// The compressor is paused while the door is open
+fn door_open(status: &FridgeStatus) -> bool {
+    status.door.is_some_and(|door| door.open)
+}
+
+// Switch to a new mode and reset the time spent in the mode
+fn set_mode(status: &mut FridgeStatus, mode: Mode) {
+    status.mode = mode;
+    status.mode_ms = 0.0;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process, time::Duration};
+
+    use super::*;
+    use crate::probes::{build_sensors, SensorConfig, SensorKind};
+    use crate::simulator::Simulation;
+
+    const TICK_MS: f64 = 1000.0;
+
+    fn inputs(inside_temp: f64, operation_mode: OperationMode) -> Inputs {
+        Inputs {
+            inside_temp: Some(inside_temp),
+            outside_temp: None,
+            air_temp: None,
+            target_temp: 18.0,
+            operation_mode,
+            relay: None,
+            manual_override: None,
+            door: None,
+            relay_feedback: RelayFeedback::default(),
+            strategy: ControlStrategy::Pid,
+            gains: Gains {
+                p: 8.0,
+                i: 0.0,
+                d: 0.0,
+            },
+            pid_limits: PidLimits::default(),
+            cascade: None,
+            feed_forward: FeedForward::default(),
+            timing: Timing::default(),
+        }
+    }
+
+    fn manual_override(compressor: bool, heater: bool, unprotected: bool) -> ManualOverride {
+        ManualOverride {
+            compressor,
+            heater,
+            duration_ms: 600000.0,
+            unprotected,
+            remaining_ms: 600000.0,
+        }
+    }
+
+    // Step until the outputs change, returns the time it took
+    fn run_until_change(controller: &mut Controller, inputs: &Inputs, max_ms: f64) -> f64 {
+        let outputs = controller.outputs();
+        let mut elapsed_ms = 0.0;
+        while controller.step(inputs, TICK_MS) == outputs {
+            elapsed_ms += TICK_MS;
+            assert!(
+                elapsed_ms <= max_ms,
+                "outputs did not change in {} ms",
+                max_ms
+            );
+        }
+        elapsed_ms
+    }
+
+    // A controller that has been cooling for a step
+    fn cooling_controller() -> Controller {
+        let mut controller = Controller::new();
+        run_until_change(
+            &mut controller,
+            &inputs(25.0, OperationMode::Cooling),
+            100000.0,
+        );
+        assert_eq!(controller.status().mode, Mode::Cooling);
+        controller
+    }
+
+    #[test]
+    fn compressor_waits_for_minimum_idle_time() {
+        let mut controller = Controller::new();
+        let warm = inputs(25.0, OperationMode::Cooling);
+        let elapsed_ms = run_until_change(&mut controller, &warm, 100000.0);
+        assert_eq!(elapsed_ms, Timing::default().minimum_idle_time_cooling_ms);
+        assert_eq!(
+            controller.outputs(),
+            Outputs {
+                compressor: true,
+                heater: false
+            }
+        );
+    }
+
+    #[test]
+    fn compressor_runs_for_minimum_cool_time() {
+        let mut controller = cooling_controller();
+        // Cold enough, but the compressor keeps running for its minimum time
+        let cold = inputs(15.0, OperationMode::Cooling);
+        let elapsed_ms = run_until_change(&mut controller, &cold, 100000.0);
+        assert_eq!(elapsed_ms + TICK_MS, Timing::default().minimum_cool_time_ms);
+        assert_eq!(controller.status().mode, Mode::Idle);
+    }
+
+    #[test]
+    fn heater_waits_for_idle_time_and_runs_for_minimum_heat_time() {
+        let mut controller = Controller::new();
+        let cold = inputs(15.0, OperationMode::Heating);
+        let elapsed_ms = run_until_change(&mut controller, &cold, 100000.0);
+        assert_eq!(elapsed_ms, Timing::default().minimum_idle_time_heating_ms);
+        assert!(controller.outputs().heater);
+
+        let warm = inputs(25.0, OperationMode::Heating);
+        let elapsed_ms = run_until_change(&mut controller, &warm, 100000.0);
+        assert_eq!(elapsed_ms + TICK_MS, Timing::default().minimum_heat_time_ms);
+        assert_eq!(controller.status().mode, Mode::Idle);
+    }
+
+    #[test]
+    fn relays_stop_when_the_correction_turns_around() {
+        // Too cold while cooling asks for more than the duty cycle that has run,
+        // the compressor stops after its minimum time instead of cooling on
+        let mut controller = cooling_controller();
+        let overcooled = inputs(12.0, OperationMode::Cooling);
+        let elapsed_ms = run_until_change(&mut controller, &overcooled, 100000.0);
+        assert_eq!(elapsed_ms + TICK_MS, Timing::default().minimum_cool_time_ms);
+        assert!(controller.status().target_duty_cycle > controller.status().duty_cycle);
+
+        let mut controller = Controller::new();
+        run_until_change(
+            &mut controller,
+            &inputs(10.0, OperationMode::Heating),
+            100000.0,
+        );
+        assert!(controller.outputs().heater);
+        let overheated = inputs(24.0, OperationMode::Heating);
+        let elapsed_ms = run_until_change(&mut controller, &overheated, 100000.0);
+        assert_eq!(elapsed_ms + TICK_MS, Timing::default().minimum_heat_time_ms);
+        assert!(controller.status().target_duty_cycle > controller.status().duty_cycle);
+    }
+
+    fn cascade_inputs(inside_temp: f64, air_temp: Option<f64>) -> Inputs {
+        Inputs {
+            air_temp,
+            gains: Gains {
+                p: 2.0,
+                i: 0.0,
+                d: 0.0,
+            },
+            cascade: Some(Cascade::default()),
+            ..inputs(inside_temp, OperationMode::Cooling)
+        }
+    }
+
+    #[test]
+    fn cascade_sets_the_air_setpoint() {
+        let mut controller = Controller::new();
+        // The outer loop asks for air 4 °C below the target, the air is 1 °C above that
+        controller.step(&cascade_inputs(20.0, Some(15.0)), TICK_MS);
+        let status = controller.status();
+        assert_eq!(status.air_setpoint, Some(14.0));
+        assert_eq!(status.pid_terms["inside"].output, -4.0);
+        assert_eq!(status.pid_terms["air"].output, -10.0);
+        assert_eq!(status.correction, -10.0);
+
+        // The air setpoint stays within max_air_deviation of the target
+        controller.step(&cascade_inputs(25.0, Some(15.0)), TICK_MS);
+        assert_eq!(controller.status().air_setpoint, Some(13.0));
+
+        // Without an air temperature the inner loop controls the inside temperature
+        controller.step(&cascade_inputs(20.0, None), TICK_MS);
+        let status = controller.status();
+        assert_eq!(status.air_setpoint, None);
+        assert_eq!(status.correction, -20.0);
+    }
+
+    #[test]
+    fn cascade_takes_over_without_a_jump() {
+        let mut inputs = cascade_inputs(20.0, Some(15.0));
+        inputs.gains.i = 0.1;
+        inputs.cascade = Some(Cascade {
+            i: 0.1,
+            ..Cascade::default()
+        });
+        let mut controller = Controller::new();
+        let overridden = Inputs {
+            manual_override: Some(manual_override(false, false, false)),
+            ..inputs.clone()
+        };
+        for _ in 0..100 {
+            controller.step(&overridden, TICK_MS);
+        }
+        // Both loops followed the correction of 0 during the override
+        controller.step(&inputs, TICK_MS);
+        assert!(controller.status().correction.abs() < 2.0);
+        // Without tracking the proportional terms alone ask for -10
+        let mut untracked = Controller::new();
+        untracked.step(&inputs, TICK_MS);
+        assert_eq!(untracked.status().correction, -10.0);
+    }
+
+    #[test]
+    fn feed_forward_cools_for_a_warm_room() {
+        let room = |outside_temp: f64| Inputs {
+            outside_temp: Some(outside_temp),
+            feed_forward: FeedForward {
+                enabled: true,
+                gain: 2.0,
+            },
+            pid_limits: PidLimits {
+                output_min: -20.0,
+                output_max: 10.0,
+                ..PidLimits::default()
+            },
+            ..inputs(18.5, OperationMode::Auto)
+        };
+        let mut controller = Controller::new();
+        // The PID controller asks for -4, the warm room for 6 °C * 2 more
+        controller.step(&room(24.0), TICK_MS);
+        assert_eq!(controller.status().feed_forward, Some(-12.0));
+        assert_eq!(controller.status().correction, -16.0);
+        // A cold room asks for heating, within the output limits
+        controller.step(&room(10.0), TICK_MS);
+        assert_eq!(controller.status().feed_forward, Some(16.0));
+        assert_eq!(controller.status().correction, 10.0);
+        controller.step(&room(40.0), TICK_MS);
+        assert_eq!(controller.status().correction, -20.0);
+    }
+
+    #[test]
+    fn auto_switches_operation_mode_after_switch_time() {
+        let timing = Timing::default();
+        let mut controller = Controller::new();
+        controller.step(&inputs(15.0, OperationMode::Auto), TICK_MS);
+        assert_eq!(controller.status().operation_mode, OperationMode::Heating);
+
+        // Too warm while heating, Auto only switches to cooling after the switch time
+        let warm = inputs(25.0, OperationMode::Auto);
+        let mut elapsed_ms = TICK_MS;
+        while controller.status().operation_mode == OperationMode::Heating {
+            assert!(!controller.step(&warm, TICK_MS).heater);
+            elapsed_ms += TICK_MS;
+            assert!(elapsed_ms <= timing.minimum_heating_cooling_switch_time_ms + 2.0 * TICK_MS);
+        }
+        assert!(elapsed_ms > timing.minimum_heating_cooling_switch_time_ms);
+        assert_eq!(controller.status().operation_mode, OperationMode::Cooling);
+
+        // The compressor still waits for its minimum idle time after the switch
+        let elapsed_ms = run_until_change(&mut controller, &warm, 100000.0);
+        assert_eq!(elapsed_ms + TICK_MS, timing.minimum_idle_time_cooling_ms);
+        assert!(controller.outputs().compressor);
+    }
+
+    #[test]
+    fn auto_does_not_switch_operation_mode_inside_hysteresis_band() {
+        let timing = Timing::default();
+        let mut at_target = inputs(18.0, OperationMode::Auto);
+        at_target.strategy = ControlStrategy::Hysteresis { band: 1.0 };
+        let mut controller = Controller::new();
+        controller.step(&at_target, TICK_MS);
+        let operation_mode = controller.status().operation_mode;
+
+        // Idle without a correction, there is no reason to switch
+        let mut elapsed_ms = TICK_MS;
+        while elapsed_ms < 3.0 * timing.minimum_heating_cooling_switch_time_ms {
+            let outputs = controller.step(&at_target, TICK_MS);
+            assert!(!outputs.compressor && !outputs.heater);
+            assert_eq!(controller.status().operation_mode, operation_mode);
+            elapsed_ms += TICK_MS;
+        }
+
+        // Leaving the band on the other side switches right away, it idled long enough
+        let mut other_side = at_target.clone();
+        other_side.inside_temp = Some(match operation_mode {
+            OperationMode::Heating => 19.0,
+            _ => 17.0,
+        });
+        controller.step(&other_side, TICK_MS);
+        assert_ne!(controller.status().operation_mode, operation_mode);
+    }
+
+    // The control loop on fake relay pins, with a static sensor and the simulated clock
+    #[test]
+    fn fake_relays_record_toggle_times_on_the_clock() {
+        let clock: Arc<dyn Clock> = Arc::new(Simulation::new(20.0, 1e6).unwrap().clock());
+        let log = env::temp_dir().join(format!("frust-relays-{}.jsonl", process::id()));
+        let relays = RelayConfig::default().faked(&log).open(&clock).unwrap();
+        let mut actuators = RelayPins {
+            compressor: relays.compressor.clone(),
+            heater: relays.heater.clone(),
+        };
+        let static_sensor = SensorConfig::new("inside", SensorKind::Static { value: 25.0 });
+        let (mut sensors, _) = build_sensors(&[static_sensor]).unwrap();
+        let mut controller = Controller::new();
+
+        // Times the controller switched the compressor, in ms since the start
+        let mut expected = Vec::new();
+        let start = clock.now();
+        let mut now = start;
+        while now.duration_since(start) < Duration::from_secs(7200) {
+            let delta_ms = clock.now().duration_since(now).as_millis() as f64;
+            now = clock.now();
+            sensors[0].update(now);
+            let cooling = inputs(sensors[0].value().unwrap(), OperationMode::Cooling);
+            let previous = controller.outputs();
+            let outputs = controller.step(&cooling, delta_ms);
+            if outputs.compressor != previous.compressor {
+                expected.push(now.duration_since(start).as_millis() as i64);
+            }
+            actuators.apply(&outputs).unwrap();
+            clock.sleep(Duration::from_millis(1000));
+        }
+
+        let compressor = relays.compressor.fake().unwrap().transitions();
+        let heater = relays.heater.fake().unwrap().transitions();
+        let _ = fs::remove_file(&log);
+        let toggled: Vec<i64> = compressor[1..]
+            .iter()
+            .map(|t| (t.timestamp - compressor[0].timestamp).num_milliseconds())
+            .collect();
+        // The time in a mode is added after a step, so the compressor turns on a tick later
+        assert_eq!(
+            toggled[0] as f64,
+            Timing::default().minimum_idle_time_cooling_ms + TICK_MS
+        );
+        assert!(toggled.len() > 2);
+        assert_eq!(toggled, expected);
+        let values: Vec<u8> = compressor.iter().map(|t| t.value).collect();
+        assert!(values
+            .iter()
+            .enumerate()
+            .all(|(i, value)| *value == (i % 2) as u8));
+        assert_eq!(heater.len(), 1);
+    }
+
+    #[test]
+    fn integral_unwinds_after_overshoot() {
+        for (operation_mode, before, overshoot) in [
+            (OperationMode::Heating, 15.0, 20.0),
+            (OperationMode::Cooling, 21.0, 16.0),
+        ] {
+            let mut controller = Controller::new();
+            let mut wound_up = inputs(before, operation_mode);
+            wound_up.gains.i = 0.1;
+            for _ in 0..3000 {
+                controller.step(&wound_up, TICK_MS);
+            }
+            assert!(controller.status().pid_terms["inside"].i.abs() > 50.0);
+
+            let overshot = Inputs {
+                inside_temp: Some(overshoot),
+                ..wound_up
+            };
+            for tick in 0..36000 {
+                let outputs = controller.step(&overshot, TICK_MS);
+                // The relay may finish its minimum time while the integral unwinds
+                if tick > 3600 {
+                    assert!(!outputs.compressor && !outputs.heater);
+                }
+            }
+            let status = controller.status();
+            assert_eq!(status.pid_terms["inside"].i, 0.0);
+            assert_eq!(status.correction, 8.0 * (18.0 - overshoot));
+        }
+    }
+
+    #[test]
+    fn off_keeps_both_relays_off() {
+        let mut controller = cooling_controller();
+        let off = inputs(30.0, OperationMode::Off);
+        for _ in 0..1000 {
+            let outputs = controller.step(&off, TICK_MS);
+            assert!(!outputs.compressor && !outputs.heater);
+        }
+        assert_eq!(controller.status().operation_mode, OperationMode::Off);
+    }
+
+    #[test]
+    fn stale_inside_sensor_enters_safe_state() {
+        let mut controller = cooling_controller();
+        let stale = Inputs {
+            inside_temp: None,
+            ..inputs(25.0, OperationMode::Cooling)
+        };
+        let outputs = controller.step(&stale, TICK_MS);
+        assert!(!outputs.compressor && !outputs.heater);
+        assert!(controller.status().safe_state);
+
+        controller.step(&inputs(25.0, OperationMode::Cooling), TICK_MS);
+        assert!(!controller.status().safe_state);
+    }
+
+    #[test]
+    fn open_door_pauses_compressor() {
+        let mut controller = cooling_controller();
+        let door = |open| DoorInput {
+            open,
+            left_open_ms: f64::INFINITY,
+        };
+        let open = Inputs {
+            door: Some(door(true)),
+            ..inputs(25.0, OperationMode::Cooling)
+        };
+        for _ in 0..200 {
+            assert!(!controller.step(&open, TICK_MS).compressor);
+        }
+
+        // The door was open for longer than the minimum idle time, cooling resumes at once
+        let closed = Inputs {
+            door: Some(door(false)),
+            ..inputs(25.0, OperationMode::Cooling)
+        };
+        controller.step(&closed, TICK_MS);
+        assert!(controller.outputs().compressor);
+
+        // After a short opening the compressor waits for the rest of its idle time
+        let mut controller = cooling_controller();
+        controller.step(&open, TICK_MS);
+        let elapsed_ms = run_until_change(&mut controller, &closed, 100000.0);
+        assert_eq!(
+            elapsed_ms + TICK_MS,
+            Timing::default().minimum_idle_time_cooling_ms
+        );
+    }
+
+    #[test]
+    fn relay_feedback_faults_after_timeout() {
+        let timeout_ms = 10000.0;
+        let feedback = |compressor: bool| RelayFeedback {
+            compressor: Some(compressor),
+            heater: None,
+            timeout_ms,
+        };
+
+        // Current through the compressor while it is off
+        let mut controller = Controller::new();
+        let stuck = Inputs {
+            relay_feedback: feedback(true),
+            ..inputs(18.0, OperationMode::Off)
+        };
+        let mut elapsed_ms = 0.0;
+        while elapsed_ms < timeout_ms {
+            controller.step(&stuck, TICK_MS);
+            assert_eq!(controller.status().relay_feedback["compressor"].fault, None);
+            elapsed_ms += TICK_MS;
+        }
+        controller.step(&stuck, TICK_MS);
+        let status = controller.status();
+        assert_eq!(
+            status.relay_feedback["compressor"].fault,
+            Some(RelayFault::Stuck)
+        );
+        assert!(!status.relay_feedback.contains_key("heater"));
+
+        // The fault clears as soon as the feedback follows the relay again
+        let released = Inputs {
+            relay_feedback: feedback(false),
+            ..stuck
+        };
+        controller.step(&released, TICK_MS);
+        assert_eq!(controller.status().relay_feedback["compressor"].fault, None);
+
+        // No current while the compressor runs
+        let mut controller = cooling_controller();
+        let failed = Inputs {
+            relay_feedback: feedback(false),
+            ..inputs(25.0, OperationMode::Cooling)
+        };
+        for _ in 0..=(timeout_ms / TICK_MS) as usize {
+            controller.step(&failed, TICK_MS);
+        }
+        assert!(controller.outputs().compressor);
+        assert_eq!(
+            controller.status().relay_feedback["compressor"].fault,
+            Some(RelayFault::Failed)
+        );
+    }
+
+    #[test]
+    fn override_respects_compressor_protection() {
+        let timing = Timing::default();
+        let mut controller = Controller::new();
+        let cool = Inputs {
+            manual_override: Some(manual_override(true, false, false)),
+            ..inputs(18.0, OperationMode::Off)
+        };
+        let elapsed_ms = run_until_change(&mut controller, &cool, 100000.0);
+        assert_eq!(elapsed_ms, timing.minimum_idle_time_cooling_ms);
+        assert!(controller.outputs().compressor);
+
+        // From heating, the heater goes off first and the compressor waits again
+        let mut controller = Controller::new();
+        let heat = Inputs {
+            manual_override: Some(manual_override(false, true, false)),
+            ..inputs(18.0, OperationMode::Off)
+        };
+        assert!(controller.step(&heat, TICK_MS).heater);
+        let outputs = controller.step(&cool, TICK_MS);
+        assert!(!outputs.heater && !outputs.compressor);
+        let elapsed_ms = run_until_change(&mut controller, &cool, 100000.0);
+        assert_eq!(elapsed_ms + TICK_MS, timing.minimum_idle_time_cooling_ms);
+
+        // An unsafe override does not wait
+        let mut controller = Controller::new();
+        let unsafe_cool = Inputs {
+            manual_override: Some(manual_override(true, false, true)),
+            ..inputs(18.0, OperationMode::Off)
+        };
+        assert!(controller.step(&unsafe_cool, TICK_MS).compressor);
+    }
+}
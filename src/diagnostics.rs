@@ -0,0 +1,341 @@
+//! Fault detection from the behaviour of the fridge.
+//! The slope of the inside temperature over a window is compared with the time
+//! the compressor or heater was on: cooling for most of the window without the
+//! temperature dropping means the cooling is ineffective, the temperature
+//! dropping while idle means the compressor runs without being asked to.
+//! Faults of the relay feedback are reported alongside.
+use std::collections::VecDeque;
+
+use anyhow::{bail, Result};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::controller::{FridgeStatus, Mode, RelayFault};
+
+const MS_PER_MINUTE: f64 = 60000.0;
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct DiagnosticsConfig {
+    pub enabled: bool,
+
+    // Time over which the temperature slope is measured
+    pub window_ms: f64,
+
+    // Part of the window the compressor or heater must be on (or both off) to judge the slope
+    pub min_duty: f64,
+
+    // Change of the temperature over the window (°C) that is expected when cooling or heating
+    pub min_change: f64,
+}
+
+impl Default for DiagnosticsConfig {
+    fn default() -> DiagnosticsConfig {
+        DiagnosticsConfig {
+            enabled: true,
+            // Half an hour
+            window_ms: 1800000.0,
+            min_duty: 0.9,
+            min_change: 0.2,
+        }
+    }
+}
+
+impl DiagnosticsConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.window_ms.is_finite() || self.window_ms <= 0.0 {
+            bail!("diagnostics window_ms must be larger than 0");
+        }
+        if !(self.min_duty > 0.0 && self.min_duty <= 1.0) {
+            bail!("diagnostics min_duty must be larger than 0 and at most 1");
+        }
+        if !self.min_change.is_finite() || self.min_change <= 0.0 {
+            bail!("diagnostics min_change must be larger than 0");
+        }
+        Ok(())
+    }
+}
+
+/// Something that is wrong with the fridge, the changes are over the window (°C)
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+pub enum Fault {
+    // Cooling most of the window without the temperature dropping
+    IneffectiveCooling { window_ms: f64, change: f64 },
+    // Heating most of the window without the temperature rising
+    IneffectiveHeating { window_ms: f64, change: f64 },
+    // Temperature dropping while idle in a warmer room, the compressor runs anyway
+    CoolingWhileIdle { window_ms: f64, change: f64 },
+    // Temperature rising while idle in a colder room, the heater runs anyway
+    HeatingWhileIdle { window_ms: f64, change: f64 },
+    // Relay feedback differs from the relay state
+    Relay { relay: String, fault: RelayFault },
+}
+
+impl Fault {
+    /// Name of the fault in metrics
+    pub fn name(&self) -> String {
+        match self {
+            Fault::IneffectiveCooling { .. } => "ineffective_cooling".to_string(),
+            Fault::IneffectiveHeating { .. } => "ineffective_heating".to_string(),
+            Fault::CoolingWhileIdle { .. } => "cooling_while_idle".to_string(),
+            Fault::HeatingWhileIdle { .. } => "heating_while_idle".to_string(),
+            Fault::Relay { relay, fault } => {
+                format!("{}_relay_{}", relay, format!("{:?}", fault).to_lowercase())
+            }
+        }
+    }
+
+    /// Explanation for people
+    pub fn description(&self) -> String {
+        match self {
+            Fault::IneffectiveCooling { window_ms, change } => format!(
+                "cooling commanded for {:.0} min, temperature changed {:+.2} °C",
+                window_ms / MS_PER_MINUTE,
+                change
+            ),
+            Fault::IneffectiveHeating { window_ms, change } => format!(
+                "heating commanded for {:.0} min, temperature changed {:+.2} °C",
+                window_ms / MS_PER_MINUTE,
+                change
+            ),
+            Fault::CoolingWhileIdle { window_ms, change } => format!(
+                "temperature dropped {:.2} °C in {:.0} min while idle",
+                -change,
+                window_ms / MS_PER_MINUTE
+            ),
+            Fault::HeatingWhileIdle { window_ms, change } => format!(
+                "temperature rose {:.2} °C in {:.0} min while idle",
+                change,
+                window_ms / MS_PER_MINUTE
+            ),
+            Fault::Relay {
+                relay,
+                fault: RelayFault::Stuck,
+            } => format!("current through the {} relay while it is off", relay),
+            Fault::Relay {
+                relay,
+                fault: RelayFault::Failed,
+            } => format!("no current through the {} relay while it is on", relay),
+        }
+    }
+}
+
+// Inside temperature and mode at a point in time (ms)
+#[derive(Debug, Copy, Clone)]
+struct Sample {
+    time_ms: f64,
+    inside_temp: f64,
+    mode: Mode,
+}
+
+/// Samples of the last window and the faults found in them
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    samples: VecDeque<Sample>,
+    elapsed_ms: f64,
+    // Faults of the slope, to log when they are found and cleared
+    faults: Vec<Fault>,
+}
+
+impl Diagnostics {
+    /// Add the status of a step that took `delta_ms` and return the current faults.
+    /// Without an `outside_temp` the fridge is not judged while idle.
+    pub fn update(
+        &mut self,
+        status: &FridgeStatus,
+        outside_temp: Option<f64>,
+        config: &DiagnosticsConfig,
+        delta_ms: f64,
+    ) -> Vec<Fault> {
+        self.elapsed_ms += delta_ms;
+        // Without a fresh inside temperature there is no slope to judge
+        if !config.enabled || status.safe_state {
+            self.samples.clear();
+        } else {
+            self.samples.push_back(Sample {
+                time_ms: self.elapsed_ms,
+                inside_temp: status.inside_temp,
+                mode: status.mode,
+            });
+            while self
+                .samples
+                .front()
+                .is_some_and(|sample| self.elapsed_ms - sample.time_ms > config.window_ms)
+            {
+                self.samples.pop_front();
+            }
+        }
+
+        let faults = self.slope_faults(status.inside_temp, outside_temp, config);
+        for fault in &faults {
+            if !self.faults.iter().any(|f| f.name() == fault.name()) {
+                error!("Fault: {}", fault.description());
+            }
+        }
+        for fault in &self.faults {
+            if !faults.iter().any(|f| f.name() == fault.name()) {
+                info!("Fault cleared: {}", fault.description());
+            }
+        }
+        self.faults = faults.clone();
+
+        // The controller already logs the relay faults
+        let relay_faults = status
+            .relay_feedback
+            .iter()
+            .filter_map(|(relay, feedback)| {
+                feedback.fault.map(|fault| Fault::Relay {
+                    relay: relay.clone(),
+                    fault,
+                })
+            });
+        faults.into_iter().chain(relay_faults).collect()
+    }
+
+    // Compare the temperature change over a full window with the time spent in each mode
+    fn slope_faults(
+        &self,
+        inside_temp: f64,
+        outside_temp: Option<f64>,
+        config: &DiagnosticsConfig,
+    ) -> Vec<Fault> {
+        let (first, last) = match (self.samples.front(), self.samples.back()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Vec::new(),
+        };
+        // Allow for the last step to not end exactly at the window
+        let window_ms = last.time_ms - first.time_ms;
+        if window_ms < config.window_ms * 0.99 {
+            return Vec::new();
+        }
+        let change = self.slope() * window_ms;
+        let duty = |mode: Mode| {
+            self.samples.iter().filter(|s| s.mode == mode).count() as f64
+                / self.samples.len() as f64
+        };
+
+        let mut faults = Vec::new();
+        if duty(Mode::Cooling) >= config.min_duty && change > -config.min_change {
+            faults.push(Fault::IneffectiveCooling { window_ms, change });
+        }
+        if duty(Mode::Heating) >= config.min_duty && change < config.min_change {
+            faults.push(Fault::IneffectiveHeating { window_ms, change });
+        }
+        // An idle fridge follows the room, which is only known with an outside sensor
+        if let Some(outside_temp) = outside_temp.filter(|_| duty(Mode::Idle) >= config.min_duty) {
+            if outside_temp > inside_temp && change < -config.min_change {
+                faults.push(Fault::CoolingWhileIdle { window_ms, change });
+            }
+            if outside_temp < inside_temp && change > config.min_change {
+                faults.push(Fault::HeatingWhileIdle { window_ms, change });
+            }
+        }
+        faults
+    }
+
+    // Least squares slope of the inside temperature (°C per ms)
+    fn slope(&self) -> f64 {
+        let count = self.samples.len() as f64;
+        let mean_time = self.samples.iter().map(|s| s.time_ms).sum::<f64>() / count;
+        let mean_temp = self.samples.iter().map(|s| s.inside_temp).sum::<f64>() / count;
+        let (covariance, variance) =
+            self.samples
+                .iter()
+                .fold((0.0, 0.0), |(covariance, variance), s| {
+                    let dt = s.time_ms - mean_time;
+                    (
+                        covariance + dt * (s.inside_temp - mean_temp),
+                        variance + dt * dt,
+                    )
+                });
+        if variance == 0.0 {
+            0.0
+        } else {
+            covariance / variance
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK_MS: f64 = 10000.0;
+
+    // Run a full window plus a tick in `mode` with the inside temperature of `temp(time_ms)`
+    fn run(mode: Mode, outside_temp: Option<f64>, temp: impl Fn(f64) -> f64) -> Vec<Fault> {
+        let config = DiagnosticsConfig::default();
+        let mut diagnostics = Diagnostics::default();
+        let mut faults = Vec::new();
+        let mut time_ms = 0.0;
+        while time_ms <= config.window_ms + TICK_MS {
+            let status = FridgeStatus {
+                inside_temp: temp(time_ms),
+                mode,
+                ..FridgeStatus::default()
+            };
+            faults = diagnostics.update(&status, outside_temp, &config, TICK_MS);
+            time_ms += TICK_MS;
+        }
+        faults
+    }
+
+    // Change of the temperature over the window
+    fn change(faults: &[Fault]) -> f64 {
+        match faults {
+            [Fault::IneffectiveCooling { change, .. }]
+            | [Fault::IneffectiveHeating { change, .. }]
+            | [Fault::CoolingWhileIdle { change, .. }] => *change,
+            _ => panic!("expected one fault, got {:?}", faults),
+        }
+    }
+
+    #[test]
+    fn slope_of_a_noisy_line() {
+        let mut diagnostics = Diagnostics::default();
+        for i in 0..100 {
+            let noise = if i % 2 == 0 { 0.05 } else { -0.05 };
+            diagnostics.samples.push_back(Sample {
+                time_ms: i as f64 * TICK_MS,
+                inside_temp: 20.0 - 0.001 * i as f64 + noise,
+                mode: Mode::Cooling,
+            });
+        }
+        let per_tick = diagnostics.slope() * TICK_MS;
+        assert!(
+            (per_tick + 0.001).abs() < 1e-4,
+            "slope {} per tick",
+            per_tick
+        );
+    }
+
+    #[test]
+    fn compressor_on_without_cooling() {
+        let faults = run(Mode::Cooling, None, |_| 20.0);
+        assert!(change(&faults).abs() < 1e-9);
+    }
+
+    #[test]
+    fn heater_on_without_heating() {
+        // Slowly cooling down while the heater is on
+        let faults = run(Mode::Heating, None, |ms| 20.0 - ms / 18000000.0);
+        assert!((change(&faults) + 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cooling_while_idle_in_a_warm_room() {
+        let faults = run(Mode::Idle, Some(25.0), |ms| 20.0 - ms / 1800000.0);
+        assert!((change(&faults) + 1.0).abs() < 1e-6);
+        // Without the room temperature an idle fridge is not judged
+        assert!(run(Mode::Idle, None, |ms| 20.0 - ms / 1800000.0).is_empty());
+    }
+
+    #[test]
+    fn healthy_run_has_no_faults() {
+        // Half a degree per half hour
+        assert!(run(Mode::Cooling, Some(25.0), |ms| 20.0 - ms / 3600000.0).is_empty());
+        assert!(run(Mode::Heating, Some(10.0), |ms| 15.0 + ms / 3600000.0).is_empty());
+        assert!(run(Mode::Idle, Some(25.0), |_| 20.0).is_empty());
+    }
+}
@@ -5,17 +5,40 @@
 //! such as a rotary decoder.
 //!
 //! Subset taken from: https://github.com/rust-embedded/rust-sysfs-gpio/
-use anyhow::{bail, Result};
+//!
+//! `OutputPin` drives a pin through either this interface, the character
+//! device of `gpio_cdev` or a fake that records its transitions, as chosen
+//! per pin in the configuration.
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use std::{
-    fs::{self, File},
-    io::{self, Read, Write},
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
 };
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+use crate::clock::{Clock, SystemClock};
+use crate::gpio_cdev::{self, Line};
+
+// GPIO chip of the Raspberry Pi header pins
+pub const DEFAULT_CHIP: &str = "/dev/gpiochip0";
+
+// Directory of the sysfs GPIO interface
+pub const SYSFS_ROOT: &str = "/sys/class/gpio";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Pin {
     pin_num: u64,
+    root: PathBuf,
 }
 
+// Part of the upstream subset, not every direction is used
+#[allow(dead_code)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Direction {
     In,
@@ -24,6 +47,7 @@ pub enum Direction {
     Low,
 }
 
+#[allow(clippy::enum_variant_names, dead_code)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Edge {
     NoInterrupt,
@@ -36,8 +60,18 @@ impl Pin {
     /// Create a new Pin with the provided `pin_num`
     ///
     /// This function does not export the provided pin_num.
+    #[allow(dead_code)]
     pub fn new(pin_num: u64) -> Pin {
-        Pin { pin_num: pin_num }
+        Pin::with_root(pin_num, Path::new(SYSFS_ROOT))
+    }
+
+    /// Create a new Pin with the provided `pin_num` in another
+    /// directory than `/sys/class/gpio`, such as a test fixture
+    pub fn with_root(pin_num: u64, root: &Path) -> Pin {
+        Pin {
+            pin_num,
+            root: root.to_path_buf(),
+        }
     }
 
     /// Export the GPIO
@@ -55,8 +89,8 @@ impl Pin {
     /// 3. The requested GPIO is in use by the kernel and cannot
     ///    be exported by use in userspace
     pub fn export(&self) -> Result<&Pin> {
-        if fs::metadata(&format!("/sys/class/gpio/gpio{}", self.pin_num)).is_err() {
-            let mut export_file = File::create("/sys/class/gpio/export")?;
+        if fs::metadata(self.root.join(format!("gpio{}", self.pin_num))).is_err() {
+            let mut export_file = File::create(self.root.join("export"))?;
             export_file.write_all(format!("{}", self.pin_num).as_bytes())?;
         }
         Ok(self)
@@ -88,6 +122,16 @@ impl Pin {
         Ok(self)
     }
 
+    /// Configure the values of the Pin as inverted
+    ///
+    /// With `active_low` a value of 1 sets the pin low and a low pin
+    /// reads as 0. The initial value of `Direction::High` and
+    /// `Direction::Low` is not inverted.
+    pub fn set_active_low(&self, active_low: bool) -> Result<&Pin> {
+        self.write_to_device_file("active_low", if active_low { "1" } else { "0" })?;
+        Ok(self)
+    }
+
     /// Set the value of the Pin
     ///
     /// This will set the value of the pin either high or low.
@@ -122,19 +166,358 @@ impl Pin {
         }
     }
 
+    /// Set the edge on which `PinPoller::poll` wakes up
+    ///
+    /// This only works for inputs that can generate interrupts.
+    /// `Edge::NoInterrupt` turns interrupts off.
+    pub fn set_edge(&self, edge: Edge) -> Result<&Pin> {
+        self.write_to_device_file(
+            "edge",
+            match edge {
+                Edge::NoInterrupt => "none",
+                Edge::RisingEdge => "rising",
+                Edge::FallingEdge => "falling",
+                Edge::BothEdges => "both",
+            },
+        )?;
+        Ok(self)
+    }
+
+    /// Get a PinPoller to wait for the edge set with `set_edge`
+    pub fn get_poller(&self) -> Result<PinPoller> {
+        let gpio_path = self.root.join(format!("gpio{}/value", self.pin_num));
+        let mut file = File::open(&gpio_path)?;
+        // The interrupt stays pending until the value is read once
+        file.read_to_string(&mut String::new())?;
+        Ok(PinPoller {
+            pin_num: self.pin_num,
+            file,
+        })
+    }
+
     /// Write all of the provided contents to the specified devFile
     fn write_to_device_file(&self, dev_file_name: &str, value: &str) -> io::Result<()> {
-        let gpio_path = format!("/sys/class/gpio/gpio{}/{}", self.pin_num, dev_file_name);
+        let gpio_path = self
+            .root
+            .join(format!("gpio{}/{}", self.pin_num, dev_file_name));
         let mut dev_file = File::create(&gpio_path)?;
         dev_file.write_all(value.as_bytes())?;
         Ok(())
     }
 
     fn read_from_device_file(&self, dev_file_name: &str) -> io::Result<String> {
-        let gpio_path = format!("/sys/class/gpio/gpio{}/{}", self.pin_num, dev_file_name);
+        let gpio_path = self
+            .root
+            .join(format!("gpio{}/{}", self.pin_num, dev_file_name));
         let mut dev_file = File::open(&gpio_path)?;
         let mut s = String::new();
         dev_file.read_to_string(&mut s)?;
         Ok(s)
     }
 }
+
+/// Waits for interrupts on the value file of a sysfs pin
+#[derive(Debug)]
+pub struct PinPoller {
+    pin_num: u64,
+    file: File,
+}
+
+impl PinPoller {
+    /// Block until an interrupt occurs or `timeout` passes
+    ///
+    /// Returns the value of the pin after the interrupt, or None
+    /// when the timeout passed first.
+    pub fn poll(&mut self, timeout: Duration) -> Result<Option<u8>> {
+        let start = Instant::now();
+        if !gpio_cdev::poll(&self.file, libc::POLLPRI | libc::POLLERR, timeout)? {
+            // Files without interrupts, like a test fixture, return at once
+            thread::sleep(timeout.saturating_sub(start.elapsed()));
+            return Ok(None);
+        }
+        let mut s = String::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_string(&mut s)?;
+        match s.trim() {
+            "1" => Ok(Some(1)),
+            "0" => Ok(Some(0)),
+            other => bail!("value file contents {} of pin {}", other, self.pin_num),
+        }
+    }
+}
+
+/// A GPIO pin and the interface to access it with
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "interface")]
+pub enum PinConfig {
+    // Line of a GPIO character device, released by the kernel when the process exits
+    Cdev {
+        #[serde(default = "default_chip")]
+        chip: PathBuf,
+        line: u32,
+    },
+    // Deprecated sysfs interface, the pin stays exported after the process exits
+    Sysfs {
+        pin: u64,
+        #[serde(default = "default_sysfs_root")]
+        root: PathBuf,
+    },
+    // No hardware, the transitions are kept in memory and appended to `log` as JSON lines
+    Fake {
+        #[serde(default)]
+        log: Option<PathBuf>,
+    },
+}
+
+fn default_chip() -> PathBuf {
+    PathBuf::from(DEFAULT_CHIP)
+}
+
+fn default_sysfs_root() -> PathBuf {
+    PathBuf::from(SYSFS_ROOT)
+}
+
+impl PinConfig {
+    /// Open the pin of `name` as an output with the initial value `value`.
+    /// With `active_low` the values are inverted, 1 sets the pin low.
+    /// The pin gets its initial value as it becomes an output, so it never glitches.
+    /// A fake pin records the times of its transitions on `clock`.
+    pub fn open_output(
+        &self,
+        name: &str,
+        active_low: bool,
+        value: u8,
+        clock: &Arc<dyn Clock>,
+    ) -> Result<OutputPin> {
+        let logical = if value == 0 {
+            Direction::Low
+        } else {
+            Direction::High
+        };
+        match self {
+            PinConfig::Cdev { chip, line } => Ok(OutputPin::Cdev(Line::request(
+                chip,
+                *line,
+                &format!("frust {}", name),
+                logical,
+                active_low,
+                Edge::NoInterrupt,
+            )?)),
+            PinConfig::Sysfs { pin, root } => {
+                // The initial level of the direction is not inverted by active_low
+                let physical = match (logical, active_low) {
+                    (Direction::Low, true) => Direction::High,
+                    (Direction::High, true) => Direction::Low,
+                    (dir, _) => dir,
+                };
+                let pin = Pin::with_root(*pin, root);
+                pin.export()
+                    .with_context(|| format!("could not export {} pin", name))?
+                    .set_active_low(active_low)?
+                    .set_direction(physical)?;
+                Ok(OutputPin::Sysfs(pin))
+            }
+            PinConfig::Fake { log } => Ok(OutputPin::Fake(FakePin::new(
+                name,
+                log.as_deref(),
+                value,
+                clock.clone(),
+            )?)),
+        }
+    }
+
+    /// Open the pin of `name` as an input that wakes up on `edge`.
+    /// With `active_low` the values are inverted, a low pin reads as 1.
+    pub fn open_input(&self, name: &str, active_low: bool, edge: Edge) -> Result<InputPin> {
+        match self {
+            PinConfig::Cdev { chip, line } => Ok(InputPin::Cdev(Line::request(
+                chip,
+                *line,
+                &format!("frust {}", name),
+                Direction::In,
+                active_low,
+                edge,
+            )?)),
+            PinConfig::Sysfs { pin, root } => {
+                let pin = Pin::with_root(*pin, root);
+                pin.export()
+                    .with_context(|| format!("could not export {} pin", name))?
+                    .set_active_low(active_low)?
+                    .set_direction(Direction::In)?
+                    .set_edge(edge)?;
+                let poller = pin.get_poller()?;
+                Ok(InputPin::Sysfs(pin, poller))
+            }
+            // Only records its initial value, so the wall clock will do
+            PinConfig::Fake { log } => Ok(InputPin::Fake(FakePin::new(
+                name,
+                log.as_deref(),
+                0,
+                Arc::new(SystemClock),
+            )?)),
+        }
+    }
+}
+
+/// Input on either GPIO interface
+#[derive(Debug)]
+pub enum InputPin {
+    Cdev(Line),
+    Sysfs(Pin, PinPoller),
+    // Never changes, reads 0
+    Fake(FakePin),
+}
+
+impl InputPin {
+    /// Get the value of the pin (0 or 1)
+    pub fn get_value(&self) -> Result<u8> {
+        match self {
+            InputPin::Cdev(line) => line.get_value(),
+            InputPin::Sysfs(pin, _) => pin.get_value(),
+            InputPin::Fake(pin) => Ok(pin.get_value().unwrap_or(0)),
+        }
+    }
+
+    /// Wait at most `timeout` for the edge the pin was opened with,
+    /// returns the value after the edge or None when there was none
+    pub fn wait_for_edge(&mut self, timeout: Duration) -> Result<Option<u8>> {
+        match self {
+            InputPin::Cdev(line) => match line.wait_for_edge(timeout)? {
+                true => line.get_value().map(Some),
+                false => Ok(None),
+            },
+            InputPin::Sysfs(_, poller) => poller.poll(timeout),
+            InputPin::Fake(_) => {
+                thread::sleep(timeout);
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Output on either GPIO interface
+#[derive(Debug)]
+pub enum OutputPin {
+    Cdev(Line),
+    Sysfs(Pin),
+    Fake(FakePin),
+}
+
+impl OutputPin {
+    /// Set the value of the pin, 0 is off and any other value is on
+    pub fn set_value(&self, value: u8) -> Result<()> {
+        match self {
+            OutputPin::Cdev(line) => line.set_value(value),
+            OutputPin::Sysfs(pin) => pin.set_value(value).map(|_| ()),
+            OutputPin::Fake(pin) => pin.set_value(value),
+        }
+    }
+
+    /// The fake pin behind this output, to check its transitions
+    #[cfg(test)]
+    pub fn fake(&self) -> Option<&FakePin> {
+        match self {
+            OutputPin::Fake(pin) => Some(pin),
+            _ => None,
+        }
+    }
+}
+
+/// Change of the value of a fake pin
+#[derive(Debug, Clone, Serialize)]
+pub struct Transition {
+    pub pin: String,
+    pub value: u8,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Output pin without hardware that records every change of its value,
+/// so tests can check when a relay switched
+pub struct FakePin {
+    name: String,
+    transitions: Mutex<Vec<Transition>>,
+    log: Option<Mutex<File>>,
+
+    // Clock of the control loop, the timestamps follow it from the creation of the pin
+    clock: Arc<dyn Clock>,
+    created: Instant,
+    created_at: DateTime<Utc>,
+}
+
+impl fmt::Debug for FakePin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FakePin")
+            .field("name", &self.name)
+            .field("transitions", &self.transitions)
+            .finish()
+    }
+}
+
+impl FakePin {
+    /// Create a pin with the initial value `value`, which is the first transition.
+    /// With a `log` every transition is appended to it as a line of JSON.
+    pub fn new(
+        name: &str,
+        log: Option<&Path>,
+        value: u8,
+        clock: Arc<dyn Clock>,
+    ) -> Result<FakePin> {
+        let log = match log {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("could not open {}", path.display()))?,
+            )),
+            None => None,
+        };
+        let pin = FakePin {
+            name: name.to_string(),
+            transitions: Mutex::new(Vec::new()),
+            log,
+            created: clock.now(),
+            created_at: Utc::now(),
+            clock,
+        };
+        pin.record(value)?;
+        Ok(pin)
+    }
+
+    /// Set the value of the pin, only a different value is a transition
+    pub fn set_value(&self, value: u8) -> Result<()> {
+        if self.get_value() != Some((value != 0) as u8) {
+            self.record(value)?;
+        }
+        Ok(())
+    }
+
+    /// Current value, None before the first transition
+    pub fn get_value(&self) -> Option<u8> {
+        self.transitions.lock().unwrap().last().map(|t| t.value)
+    }
+
+    /// Every value the pin had, starting with the initial one
+    #[cfg(test)]
+    pub fn transitions(&self) -> Vec<Transition> {
+        self.transitions.lock().unwrap().clone()
+    }
+
+    fn record(&self, value: u8) -> Result<()> {
+        let transition = Transition {
+            pin: self.name.clone(),
+            value: (value != 0) as u8,
+            timestamp: self.created_at
+                + chrono::Duration::from_std(
+                    self.clock.now().saturating_duration_since(self.created),
+                )?,
+        };
+        if let Some(log) = &self.log {
+            let mut log = log.lock().unwrap();
+            serde_json::to_writer(&mut *log, &transition)?;
+            log.write_all(b"\n")?;
+        }
+        self.transitions.lock().unwrap().push(transition);
+        Ok(())
+    }
+}
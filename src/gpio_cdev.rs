@@ -0,0 +1,239 @@
+//! Access GPIO lines via the GPIO character device (`/dev/gpiochipN`)
+//! with the v2 line request ioctls of `linux/gpio.h`.
+//! Unlike sysfs, a requested line belongs to the file descriptor of the
+//! request, so the kernel releases it when the process exits or crashes.
+use anyhow::{bail, Context, Result};
+use std::{
+    fs::File,
+    io::{self, Read},
+    mem,
+    os::unix::io::{AsRawFd, FromRawFd},
+    path::Path,
+    time::Duration,
+};
+
+use crate::gpio::{Direction, Edge};
+
+const GPIO_V2_LINES_MAX: usize = 64;
+const GPIO_V2_LINE_NUM_ATTRS_MAX: usize = 10;
+const GPIO_MAX_NAME_SIZE: usize = 32;
+
+const GPIO_V2_LINE_FLAG_ACTIVE_LOW: u64 = 1 << 1;
+const GPIO_V2_LINE_FLAG_INPUT: u64 = 1 << 2;
+const GPIO_V2_LINE_FLAG_OUTPUT: u64 = 1 << 3;
+const GPIO_V2_LINE_FLAG_EDGE_RISING: u64 = 1 << 4;
+const GPIO_V2_LINE_FLAG_EDGE_FALLING: u64 = 1 << 5;
+
+const GPIO_V2_LINE_ATTR_ID_OUTPUT_VALUES: u32 = 2;
+
+#[repr(C)]
+#[derive(Default)]
+struct LineValues {
+    bits: u64,
+    mask: u64,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct LineAttribute {
+    id: u32,
+    padding: u32,
+    // Union of the flags, output values and debounce period
+    value: u64,
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct LineConfigAttribute {
+    attr: LineAttribute,
+    mask: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct LineConfig {
+    flags: u64,
+    num_attrs: u32,
+    padding: [u32; 5],
+    attrs: [LineConfigAttribute; GPIO_V2_LINE_NUM_ATTRS_MAX],
+}
+
+#[repr(C)]
+struct LineRequest {
+    offsets: [u32; GPIO_V2_LINES_MAX],
+    consumer: [u8; GPIO_MAX_NAME_SIZE],
+    config: LineConfig,
+    num_lines: u32,
+    event_buffer_size: u32,
+    padding: [u32; 5],
+    fd: i32,
+}
+
+// Size of a `gpio_v2_line_event` read from a line request with edge detection
+const LINE_EVENT_SIZE: usize = 48;
+
+// The ioctl numbers encode the struct sizes, which must match the kernel
+const _: () = assert!(mem::size_of::<LineValues>() == 16);
+const _: () = assert!(mem::size_of::<LineConfig>() == 272);
+const _: () = assert!(mem::size_of::<LineRequest>() == 592);
+
+// _IOWR(0xB4, nr, size)
+const fn iowr(nr: u64, size: usize) -> u64 {
+    (3 << 30) | ((size as u64) << 16) | (0xB4 << 8) | nr
+}
+
+const GPIO_V2_GET_LINE_IOCTL: u64 = iowr(0x07, mem::size_of::<LineRequest>());
+const GPIO_V2_LINE_GET_VALUES_IOCTL: u64 = iowr(0x0E, mem::size_of::<LineValues>());
+const GPIO_V2_LINE_SET_VALUES_IOCTL: u64 = iowr(0x0F, mem::size_of::<LineValues>());
+
+/// A single requested line of a GPIO chip
+#[derive(Debug)]
+pub struct Line {
+    // File descriptor of the line request, closing it releases the line
+    request: File,
+    offset: u32,
+}
+
+impl Line {
+    /// Request line `offset` of `chip`, as an input or as an output with
+    /// the initial value of the direction (`Out` starts low).
+    /// The line is requested with its initial value, so it never glitches.
+    /// An `active_low` line inverts all values, including the initial one.
+    /// The `edge` of an input can be waited for with `wait_for_edge`.
+    ///
+    /// `consumer` shows up in `gpioinfo` as the user of the line.
+    pub fn request(
+        chip: &Path,
+        offset: u32,
+        consumer: &str,
+        dir: Direction,
+        active_low: bool,
+        edge: Edge,
+    ) -> Result<Line> {
+        let chip_file = File::open(chip)
+            .with_context(|| format!("could not open GPIO chip {}", chip.display()))?;
+
+        let mut config = LineConfig::default();
+        match dir {
+            Direction::In => config.flags = GPIO_V2_LINE_FLAG_INPUT,
+            Direction::Out | Direction::Low | Direction::High => {
+                config.flags = GPIO_V2_LINE_FLAG_OUTPUT;
+                config.num_attrs = 1;
+                config.attrs[0] = LineConfigAttribute {
+                    attr: LineAttribute {
+                        id: GPIO_V2_LINE_ATTR_ID_OUTPUT_VALUES,
+                        padding: 0,
+                        value: (dir == Direction::High) as u64,
+                    },
+                    mask: 1,
+                };
+            }
+        }
+        if active_low {
+            config.flags |= GPIO_V2_LINE_FLAG_ACTIVE_LOW;
+        }
+        if dir == Direction::In {
+            config.flags |= match edge {
+                Edge::NoInterrupt => 0,
+                Edge::RisingEdge => GPIO_V2_LINE_FLAG_EDGE_RISING,
+                Edge::FallingEdge => GPIO_V2_LINE_FLAG_EDGE_FALLING,
+                Edge::BothEdges => GPIO_V2_LINE_FLAG_EDGE_RISING | GPIO_V2_LINE_FLAG_EDGE_FALLING,
+            };
+        }
+        let mut request = LineRequest {
+            offsets: [0; GPIO_V2_LINES_MAX],
+            consumer: [0; GPIO_MAX_NAME_SIZE],
+            config,
+            num_lines: 1,
+            event_buffer_size: 0,
+            padding: [0; 5],
+            fd: -1,
+        };
+        request.offsets[0] = offset;
+        // Leave room for the terminating zero
+        let name = consumer.as_bytes();
+        let len = name.len().min(GPIO_MAX_NAME_SIZE - 1);
+        request.consumer[..len].copy_from_slice(&name[..len]);
+
+        ioctl(&chip_file, GPIO_V2_GET_LINE_IOCTL, &mut request).with_context(|| {
+            format!(
+                "could not request line {} of GPIO chip {}",
+                offset,
+                chip.display()
+            )
+        })?;
+        if request.fd < 0 {
+            bail!("GPIO chip {} returned no line request", chip.display());
+        }
+        Ok(Line {
+            // Safety: the kernel handed over a new file descriptor that nothing else owns
+            request: unsafe { File::from_raw_fd(request.fd) },
+            offset,
+        })
+    }
+
+    /// Set the value of the line, 0 is low and any other value is high
+    pub fn set_value(&self, value: u8) -> Result<()> {
+        let mut values = LineValues {
+            bits: (value != 0) as u64,
+            mask: 1,
+        };
+        ioctl(&self.request, GPIO_V2_LINE_SET_VALUES_IOCTL, &mut values)
+            .with_context(|| format!("could not set GPIO line {}", self.offset))
+    }
+
+    /// Get the value of the line (0 or 1)
+    pub fn get_value(&self) -> Result<u8> {
+        let mut values = LineValues { bits: 0, mask: 1 };
+        ioctl(&self.request, GPIO_V2_LINE_GET_VALUES_IOCTL, &mut values)
+            .with_context(|| format!("could not get GPIO line {}", self.offset))?;
+        Ok((values.bits & 1) as u8)
+    }
+
+    /// Wait at most `timeout` for an edge of an input, returns whether there was one
+    pub fn wait_for_edge(&mut self, timeout: Duration) -> Result<bool> {
+        if !poll(&self.request, libc::POLLIN, timeout)? {
+            return Ok(false);
+        }
+        // The value is read separately, the event only wakes us up
+        let mut event = [0; LINE_EVENT_SIZE];
+        self.request
+            .read_exact(&mut event)
+            .with_context(|| format!("could not read event of GPIO line {}", self.offset))?;
+        Ok(true)
+    }
+}
+
+/// Wait at most `timeout` for `events` on `file`, returns whether they happened
+pub fn poll(file: &File, events: libc::c_short, timeout: Duration) -> io::Result<bool> {
+    let mut fds = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events,
+        revents: 0,
+    };
+    let timeout_ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    // Safety: a single valid pollfd
+    let result = unsafe { libc::poll(&mut fds, 1, timeout_ms) };
+    match result {
+        r if r < 0 => {
+            let e = io::Error::last_os_error();
+            // A signal is not an error, the caller waits again
+            if e.kind() == io::ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(e)
+            }
+        }
+        0 => Ok(false),
+        _ => Ok(fds.revents & events != 0),
+    }
+}
+
+fn ioctl<T>(file: &File, request: u64, arg: &mut T) -> io::Result<()> {
+    // Safety: `arg` is the struct the request number was built from
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), request as _, arg as *mut T) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
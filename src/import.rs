@@ -0,0 +1,385 @@
+//! Import fermentation schedules from BeerXML and BeerJSON recipe exports.
+//! Every fermentation stage or step becomes a step of a `Profile`,
+//! fields that have no equivalent in a profile are reported as ignored.
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::profiles::{Condition, Profile, Step};
+
+const MS_PER_DAY: f64 = 86_400_000.0;
+
+// BeerXML fermentation stages in order, with their temperature and age fields
+const BEERXML_STAGES: [(&str, &str); 3] = [
+    ("PRIMARY_TEMP", "PRIMARY_AGE"),
+    ("SECONDARY_TEMP", "SECONDARY_AGE"),
+    ("TERTIARY_TEMP", "TERTIARY_AGE"),
+];
+
+/// Converted profile and the fields of the recipe that were not used
+#[derive(Debug, Serialize)]
+pub struct Import {
+    pub profile: Profile,
+    pub ignored: Vec<String>,
+}
+
+/// Convert a BeerXML or BeerJSON document, the format is detected from the content
+pub fn import_profile(document: &str) -> Result<Import> {
+    let import = if document.trim_start().starts_with('<') {
+        import_beerxml(document)?
+    } else {
+        import_beerjson(document)?
+    };
+    import.profile.validate()?;
+    Ok(import)
+}
+
+fn import_beerxml(document: &str) -> Result<Import> {
+    let doc = roxmltree::Document::parse(document).context("invalid BeerXML")?;
+    let mut recipes = doc.descendants().filter(|node| node.has_tag_name("RECIPE"));
+    let recipe = recipes.next().context("no RECIPE in BeerXML")?;
+    let mut ignored: Vec<String> = recipes
+        .enumerate()
+        .map(|(i, _)| format!("RECIPE[{}]", i + 1))
+        .collect();
+
+    let field = |name: &str| {
+        recipe
+            .children()
+            .find(|node| node.has_tag_name(name))
+            .and_then(|node| node.text())
+            .map(str::trim)
+    };
+    let number = |name: &str| -> Result<Option<f64>> {
+        field(name)
+            .map(|text| {
+                text.parse::<f64>()
+                    .with_context(|| format!("invalid {} {}", name, text))
+            })
+            .transpose()
+    };
+
+    let mut used = vec!["NAME", "FERMENTATION_STAGES", "AGE", "AGE_TEMP"];
+    let stages = match number("FERMENTATION_STAGES")? {
+        Some(stages) => {
+            if stages.fract() != 0.0 || !(0.0..=BEERXML_STAGES.len() as f64).contains(&stages) {
+                bail!(
+                    "FERMENTATION_STAGES must be a whole number up to {}, got {}",
+                    BEERXML_STAGES.len(),
+                    stages
+                );
+            }
+            stages as usize
+        }
+        None => BEERXML_STAGES
+            .iter()
+            .take_while(|(temp, _)| field(temp).is_some())
+            .count(),
+    };
+    let mut steps = Vec::new();
+    for (temp, age) in BEERXML_STAGES.iter().take(stages) {
+        let temperature = number(temp)?.ok_or_else(|| anyhow!("{} is missing", temp))?;
+        steps.push(match number(age)? {
+            Some(days) => Step::Hold {
+                temperature,
+                duration_ms: days * MS_PER_DAY,
+            },
+            None => Step::HoldUntil {
+                temperature,
+                condition: Condition::Manual,
+            },
+        });
+        used.push(temp);
+        used.push(age);
+    }
+    if let Some(temperature) = number("AGE_TEMP")? {
+        steps.push(Step::Hold {
+            temperature,
+            duration_ms: number("AGE")?.unwrap_or(0.0) * MS_PER_DAY,
+        });
+    }
+
+    for node in recipe.children().filter(|node| node.is_element()) {
+        let name = node.tag_name().name();
+        if !used.contains(&name) && !ignored.iter().any(|ignored| ignored == name) {
+            ignored.push(name.to_string());
+        }
+    }
+
+    Ok(Import {
+        profile: Profile {
+            name: field("NAME").unwrap_or("imported").to_string(),
+            steps,
+        },
+        ignored,
+    })
+}
+
+fn import_beerjson(document: &str) -> Result<Import> {
+    let doc: Value = serde_json::from_str(document).context("invalid BeerJSON")?;
+    let beerjson = doc.get("beerjson").context("no beerjson object")?;
+    let mut ignored = unused_keys(beerjson, &["version", "recipes", "fermentations"], "");
+
+    // A recipe with its fermentation, or a separate fermentation procedure
+    let recipes = array(beerjson, "recipes");
+    let (name, fermentation, path) = match recipes.first() {
+        Some(recipe) => {
+            ignored.extend(unused_keys(
+                recipe,
+                &["name", "fermentation"],
+                "recipes[0].",
+            ));
+            ignored.extend((1..recipes.len()).map(|i| format!("recipes[{}]", i)));
+            (
+                recipe.get("name").and_then(Value::as_str),
+                recipe
+                    .get("fermentation")
+                    .context("recipe has no fermentation")?,
+                "recipes[0].fermentation",
+            )
+        }
+        None => (
+            None,
+            array(beerjson, "fermentations")
+                .first()
+                .context("no recipes or fermentations in BeerJSON")?,
+            "fermentations[0]",
+        ),
+    };
+    ignored.extend(unused_keys(
+        fermentation,
+        &["name", "fermentation_steps"],
+        &format!("{}.", path),
+    ));
+
+    let mut steps = Vec::new();
+    for (i, step) in array(fermentation, "fermentation_steps").iter().enumerate() {
+        let step_path = format!("{}.fermentation_steps[{}]", path, i);
+        ignored.extend(unused_keys(
+            step,
+            &["name", "start_temperature", "end_temperature", "step_time"],
+            &format!("{}.", step_path),
+        ));
+        let start = temperature(step.get("start_temperature"))?;
+        let end = temperature(step.get("end_temperature"))?;
+        let duration_ms = duration_ms(step.get("step_time"))?;
+        steps.push(match (start, end, duration_ms) {
+            (Some(start), Some(end), Some(duration_ms)) if start != end => Step::Ramp {
+                temperature: end,
+                duration_ms,
+            },
+            // Without a duration the step ends when the end temperature is reached
+            (Some(start), Some(end), None) if start != end => Step::HoldUntil {
+                temperature: end,
+                condition: if end < start {
+                    Condition::Below { temperature: end }
+                } else {
+                    Condition::Above { temperature: end }
+                },
+            },
+            (start, end, duration_ms) => {
+                let temperature = start
+                    .or(end)
+                    .ok_or_else(|| anyhow!("{} has no temperature", step_path))?;
+                match duration_ms {
+                    Some(duration_ms) => Step::Hold {
+                        temperature,
+                        duration_ms,
+                    },
+                    None => Step::HoldUntil {
+                        temperature,
+                        condition: Condition::Manual,
+                    },
+                }
+            }
+        });
+    }
+
+    Ok(Import {
+        profile: Profile {
+            name: name
+                .or_else(|| fermentation.get("name").and_then(Value::as_str))
+                .unwrap_or("imported")
+                .to_string(),
+            steps,
+        },
+        ignored,
+    })
+}
+
+fn array<'a>(value: &'a Value, key: &str) -> &'a [Value] {
+    value
+        .get(key)
+        .and_then(Value::as_array)
+        .map_or(&[], Vec::as_slice)
+}
+
+// Keys of an object that are not in `used`, prefixed with the path of the object
+fn unused_keys(value: &Value, used: &[&str], prefix: &str) -> Vec<String> {
+    value.as_object().map_or(Vec::new(), |object| {
+        object
+            .keys()
+            .filter(|key| !used.contains(&key.as_str()))
+            .map(|key| format!("{}{}", prefix, key))
+            .collect()
+    })
+}
+
+// BeerJSON temperature in °C
+fn temperature(value: Option<&Value>) -> Result<Option<f64>> {
+    let (unit, value) = match unit_value(value)? {
+        Some(unit_value) => unit_value,
+        None => return Ok(None),
+    };
+    Ok(Some(match unit {
+        "C" => value,
+        "F" => (value - 32.0) * 5.0 / 9.0,
+        _ => bail!("unknown temperature unit {}", unit),
+    }))
+}
+
+// BeerJSON time in ms
+fn duration_ms(value: Option<&Value>) -> Result<Option<f64>> {
+    let (unit, value) = match unit_value(value)? {
+        Some(unit_value) => unit_value,
+        None => return Ok(None),
+    };
+    let ms_per_unit = match unit {
+        "sec" => 1000.0,
+        "min" => 60_000.0,
+        "hr" => 3_600_000.0,
+        "day" => MS_PER_DAY,
+        "week" => 7.0 * MS_PER_DAY,
+        _ => bail!("unknown time unit {}", unit),
+    };
+    Ok(Some(value * ms_per_unit))
+}
+
+// BeerJSON `{"unit": .., "value": ..}` pair
+fn unit_value(value: Option<&Value>) -> Result<Option<(&str, f64)>> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let unit = value
+        .get("unit")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("missing unit in {}", value))?;
+    let number = value
+        .get("value")
+        .and_then(Value::as_f64)
+        .ok_or_else(|| anyhow!("missing value in {}", value))?;
+    Ok(Some((unit, number)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_temperature(step: &Step, expected: f64) {
+        assert!(
+            (step.temperature() - expected).abs() < 1e-9,
+            "{:?} is not at {}",
+            step,
+            expected
+        );
+    }
+
+    #[test]
+    fn imports_beerxml_stages_and_aging() {
+        let import = import_profile(include_str!("../test/recipe.xml")).unwrap();
+        assert_eq!(import.profile.name, "Dry Stout");
+        assert_eq!(
+            import.profile.steps,
+            vec![
+                Step::Hold {
+                    temperature: 20.0,
+                    duration_ms: 4.0 * MS_PER_DAY,
+                },
+                Step::Hold {
+                    temperature: 20.0,
+                    duration_ms: 7.0 * MS_PER_DAY,
+                },
+                Step::Hold {
+                    temperature: 4.0,
+                    duration_ms: 24.0 * MS_PER_DAY,
+                },
+            ]
+        );
+        // The tertiary stage is beyond FERMENTATION_STAGES
+        assert_eq!(
+            import.ignored,
+            vec![
+                "VERSION",
+                "TYPE",
+                "BREWER",
+                "BATCH_SIZE",
+                "BOIL_SIZE",
+                "BOIL_TIME",
+                "EFFICIENCY",
+                "TERTIARY_AGE",
+                "TERTIARY_TEMP",
+                "CARBONATION",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_fermentation_stages() {
+        let recipe = include_str!("../test/recipe.xml");
+        for stages in ["4", "-1", "1.5", "NaN"] {
+            let document = recipe.replace(
+                "<FERMENTATION_STAGES>2<",
+                &format!("<FERMENTATION_STAGES>{}<", stages),
+            );
+            assert!(
+                import_profile(&document).is_err(),
+                "FERMENTATION_STAGES {} was accepted",
+                stages
+            );
+        }
+    }
+
+    #[test]
+    fn imports_beerjson_steps() {
+        let import = import_profile(include_str!("../test/recipe.json")).unwrap();
+        assert_eq!(import.profile.name, "Hazy IPA");
+        let steps = &import.profile.steps;
+        assert_eq!(steps.len(), 3);
+        assert_eq!(
+            steps[0],
+            Step::Hold {
+                temperature: 19.0,
+                duration_ms: 5.0 * MS_PER_DAY,
+            }
+        );
+        assert_eq!(
+            steps[1],
+            Step::Ramp {
+                temperature: 22.0,
+                duration_ms: 2.0 * MS_PER_DAY,
+            }
+        );
+        // The cold crash in Fahrenheit has no duration, so it ends at its temperature
+        assert_temperature(&steps[2], 2.0);
+        match steps[2] {
+            Step::HoldUntil {
+                condition: Condition::Below { temperature },
+                ..
+            } => assert!((temperature - 2.0).abs() < 1e-9),
+            step => panic!("cold crash imported as {:?}", step),
+        }
+
+        let mut ignored = import.ignored;
+        ignored.sort();
+        assert_eq!(
+            ignored,
+            vec![
+                "recipes[0].author",
+                "recipes[0].fermentation.fermentation_steps[0].start_gravity",
+                "recipes[0].fermentation.fermentation_steps[1].free_rise",
+                "recipes[0].type",
+            ]
+        );
+    }
+}
@@ -1,56 +1,73 @@
-use actix::Message;
+use actix::{Actor, Addr, Message};
 use actix_files::NamedFile;
 use actix_web::dev::ServiceRequest;
 use actix_web::{error, get, web, App, Error, HttpResponse, HttpServer};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 use actix_web_httpauth::middleware::HttpAuthentication;
+use alarms::{AlarmStatus, Alarms, AlarmsConfig};
 use anyhow::{Context, Result};
+use autotune::{Autotune, AutotuneSettings, AutotuneState, TuningRule};
+use broadcast::{Broadcaster, Subscribe};
+use chrono::{DateTime, Utc};
+use clock::{Clock, SystemClock};
+use controller::{
+    Actuators, Cascade, ControlStrategy, Controller, DoorInput, FeedForward, FridgeStatus, Inputs,
+    ManualOverride, Mode, OperationMode, RelayConfig, RelayFeedback, Timing,
+};
 use core::f64;
-use gpio::Pin;
+use diagnostics::{Diagnostics, DiagnosticsConfig, Fault};
+use futures::StreamExt;
+use gpio::OutputPin;
+use import::import_profile;
 use lazy_static::lazy_static;
-use log::info;
-use pid::Pid;
-use probes::read_temperature;
-use prometheus::{opts, register_gauge, Encoder, Gauge, TextEncoder};
+use log::{debug, error, info, warn};
+use pid::{Gains, PidLimits};
+use probes::{build_sensors, PushedTemperature, SensorConfig, SensorKind, SensorReader};
+use profiles::{
+    read_active_profile, write_active_profile, ActiveProfile, Profile, ProfileStore, StoredProfile,
+};
+use prometheus::{
+    opts, register_gauge, register_gauge_vec, register_int_counter_vec, Encoder, Gauge, GaugeVec,
+    IntCounterVec, TextEncoder,
+};
 use serde::{Deserialize, Serialize};
+use simulator::Simulation;
 use std::{
+    collections::{BTreeMap, HashMap},
     env,
-    fs::File,
+    fs::{self, File},
     io::BufReader,
-    path::Path,
-    sync::{Arc, Mutex},
+    panic,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     thread,
     time::{Duration, Instant},
 };
+use switches::{DoorConfig, Switch, Switches};
 
-use crate::gpio::Direction;
-
+mod alarms;
+mod autotune;
+mod broadcast;
+mod clock;
+mod controller;
+mod diagnostics;
 mod gpio;
+mod gpio_cdev;
+mod import;
+mod pid;
 mod probes;
+mod profiles;
+mod simulator;
+mod switches;
 
-// Wait an hour before switching between heating and cooling mode
-const MINIMUM_HEATING_COOLING_SWITCH_TIME_MS: f64 = 3600000.0;
-
-// Wait an hour before switching between cooling and heating mode
-const MINIMUM_COOLING_HEATING_SWITCH_TIME_MS: f64 = 3600000.0;
-
-// Minimum of 90 s before turning the compressor on again
-const MINIMUM_IDLE_TIME_COOLING_MS: f64 = 90000.0;
-
-// Wait 10 seconds before turning on the heater again
-const MINIMUM_IDLE_TIME_HEATING_MS: f64 = 10000.0;
-
-// Minimum of 15 s of cooling before turning it off
-const MINIMUM_COOL_TIME_MS: f64 = 15000.0;
-
-// Minimum of 30 seconds heating
-const MINIMUM_HEAT_TIME_MS: f64 = 30000.0;
-
-// Duty cycle time in ms
-const DUTY_CYCLE_MS: f64 = 300000.0;
+// Save the position of the active profile every minute
+const PROFILE_SAVE_INTERVAL_MS: f64 = 60000.0;
 
-// Current duty cycle
-const MIN_DUTY_CYCLE_MS: f64 = 0.0;
+// Report unhealthy when the control loop has not ticked for 30 seconds
+const MAXIMUM_TICK_AGE_MS: i64 = 30000;
 
 // All Prometheus metrics
 lazy_static! {
@@ -77,6 +94,19 @@ lazy_static! {
         register_gauge!(opts!("pid_i", "PID controller integral gain")).unwrap();
     static ref PID_D: Gauge =
         register_gauge!(opts!("pid_d", "PID controller derivative gain")).unwrap();
+    static ref PID_TERM: GaugeVec = register_gauge_vec!(
+        opts!(
+            "pid_term",
+            "Terms of the PID loop on the inside or (with cascade control) air temperature"
+        ),
+        &["loop", "term"]
+    )
+    .unwrap();
+    static ref AIR_SETPOINT_CELCIUS: Gauge = register_gauge!(opts!(
+        "air_setpoint_celcius",
+        "Air temperature setpoint of cascade control"
+    ))
+    .unwrap();
     static ref COMPRESSOR: Gauge = register_gauge!(opts!(
         "compressor_activated",
         "Compressor is activated (1) or turned off (0)"
@@ -87,15 +117,96 @@ lazy_static! {
         "Heater is activated (1) or turned off (0)"
     ))
     .unwrap();
+    static ref SENSOR_TEMP_CELCIUS: GaugeVec = register_gauge_vec!(
+        opts!(
+            "sensor_temp_celcius",
+            "Temperature of a named sensor in Celcius"
+        ),
+        &["sensor"]
+    )
+    .unwrap();
+    static ref SENSOR_READ_FAILURES: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "sensor_read_failures_total",
+            "Failed reads of a named sensor"
+        ),
+        &["sensor"]
+    )
+    .unwrap();
+    static ref SENSOR_STALE: GaugeVec = register_gauge_vec!(
+        opts!(
+            "sensor_stale",
+            "Sensor has not been read successfully for too long (1) or is fine (0)"
+        ),
+        &["sensor"]
+    )
+    .unwrap();
+    static ref DOOR_OPEN: Gauge =
+        register_gauge!(opts!("door_open", "Fridge door is open (1) or closed (0)")).unwrap();
+    static ref RELAY_FAULT: GaugeVec = register_gauge_vec!(
+        opts!(
+            "relay_fault",
+            "Relay feedback differs from the relay state for too long (1) or is fine (0)"
+        ),
+        &["relay"]
+    )
+    .unwrap();
+    static ref FAULT: GaugeVec = register_gauge_vec!(
+        opts!("fault", "Fault found by the diagnostics is present (1)"),
+        &["fault"]
+    )
+    .unwrap();
+    static ref ALARM: GaugeVec =
+        register_gauge_vec!(opts!("alarm", "Alarm is active (1) or not (0)"), &["alarm"]).unwrap();
 }
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 struct Config {
-    // Mode of operation: Cooling or Heating
+    // Mode of operation: Cooling, Heating, Auto or Off
     pub operation_mode: OperationMode,
     pub target_temp: f64,
     pub p: f64,
     pub i: f64,
     pub d: f64,
+
+    // Temperature sensors, falls back to INSIDE_SENSOR and OUTSIDE_SENSOR when empty
+    pub sensors: Vec<SensorConfig>,
+
+    // Name of the sensor that is controlled
+    pub inside_sensor: String,
+
+    // Name of the sensor measuring the ambient temperature
+    pub outside_sensor: Option<String>,
+
+    // Name of the sensor measuring the fridge air, used by cascade control
+    pub air_sensor: String,
+
+    // PID controller, hysteresis thermostat or PID controller with scheduled gains
+    pub strategy: ControlStrategy,
+
+    // Output and term limits of the PID controllers
+    pub pid_limits: PidLimits,
+
+    // Cascade control on the air temperature, the gains above are then of the outer loop
+    pub cascade: Option<Cascade>,
+
+    // Feed-forward on the outside temperature
+    pub feed_forward: FeedForward,
+
+    // Compressor and heater protection times
+    pub timing: Timing,
+
+    // GPIO pins of the relays, on the character device or sysfs
+    pub relays: RelayConfig,
+
+    // Reed switch of the door, pauses the compressor while the door is open
+    pub door: Option<DoorConfig>,
+
+    // Detection of ineffective cooling and heating from the temperature slope
+    pub diagnostics: DiagnosticsConfig,
+
+    // Alarm rules and the command that sends their notifications
+    pub alarms: AlarmsConfig,
 }
 
 impl Default for Config {
@@ -106,76 +217,143 @@ impl Default for Config {
             p: 8.0,
             i: 0.0,
             d: 0.0,
+            sensors: Vec::new(),
+            inside_sensor: "inside".to_string(),
+            outside_sensor: Some("outside".to_string()),
+            air_sensor: "air".to_string(),
+            strategy: ControlStrategy::default(),
+            pid_limits: PidLimits::default(),
+            cascade: None,
+            feed_forward: FeedForward::default(),
+            timing: Timing::default(),
+            relays: RelayConfig::default(),
+            door: None,
+            diagnostics: DiagnosticsConfig::default(),
+            alarms: AlarmsConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    fn validate(&self) -> Result<()> {
+        for (name, value) in [
+            ("target_temp", self.target_temp),
+            ("p", self.p),
+            ("i", self.i),
+            ("d", self.d),
+        ] {
+            if !value.is_finite() {
+                anyhow::bail!("{} must be a number, got {}", name, value);
+            }
+        }
+        self.strategy.validate()?;
+        self.pid_limits.validate()?;
+        if let Some(cascade) = &self.cascade {
+            cascade.validate()?;
+        }
+        self.feed_forward.validate()?;
+        self.relays.validate()?;
+        if let Some(door) = &self.door {
+            door.validate()?;
         }
+        self.diagnostics.validate()?;
+        self.alarms.validate()?;
+        self.timing.validate()
     }
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
-pub enum Mode {
-    Idle,
-    Cooling,
-    Heating,
+// Reason a status message was published
+#[derive(Debug, Copy, Clone, Serialize, PartialEq)]
+pub enum StatusEvent {
+    // Regular control loop tick
+    Tick,
+    // Change of mode or operation mode
+    Transition,
+    // Start or end of a manual override
+    Override,
+    // Door opened or closed
+    Door,
+    // A fault was found or cleared
+    Fault,
+    // An alarm was raised or cleared
+    Alarm,
 }
 
-// Mode of operation
-// Either cooling or heating
-#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
-pub enum OperationMode {
-    Cooling,
-    Heating,
+impl StatusEvent {
+    /// Name of the event in the Server-Sent Events stream
+    pub fn name(&self) -> &'static str {
+        match self {
+            StatusEvent::Tick => "tick",
+            StatusEvent::Transition => "transition",
+            StatusEvent::Override => "override",
+            StatusEvent::Door => "door",
+            StatusEvent::Fault => "fault",
+            StatusEvent::Alarm => "alarm",
+        }
+    }
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
-pub struct FridgeStatus {
-    // Temperature in milli degrees
-    pub inside_temp: f64,
+#[derive(Message, Debug, Clone, Serialize)]
+#[rtype(result = "()")]
+struct FridgeStatusMessage {
+    pub event: StatusEvent,
 
-    // Outside temp in milli degrees
-    pub outside_temp: f64,
+    pub status: FridgeStatus,
 
-    // Correction from the PID controller
-    pub correction: f64,
+    // Time of the control tick that produced the status
+    pub timestamp: DateTime<Utc>,
+}
 
-    // Current operation mode (heating or cooling)
-    pub operation_mode: OperationMode,
+// Command line options
+struct Options {
+    // Run against the thermal simulator instead of GPIO pins and sensors
+    simulate: bool,
 
-    // Current mode while operational
-    pub mode: Mode,
+    // Simulated seconds per real second
+    acceleration: f64,
 
-    // Amount of time spent in mode (ms)
-    pub mode_ms: f64,
+    // Simulated room temperature
+    ambient_temp: f64,
 
-    // Current duty cycle (ms on / total duty cycle)
-    pub duty_cycle: f64,
+    // Convert a BeerXML or BeerJSON recipe to a profile and exit
+    import: Option<PathBuf>,
 
-    // Target duty cycle (ms on)
-    pub target_duty_cycle: f64,
+    // Drive fake relay pins that append their transitions to this file
+    fake_gpio: Option<PathBuf>,
 }
 
-impl Default for FridgeStatus {
-    fn default() -> FridgeStatus {
-        FridgeStatus {
-            inside_temp: 10.0,
-            outside_temp: 10.0,
-            correction: 0.0,
-            operation_mode: OperationMode::Heating,
-            mode: Mode::Idle,
-            mode_ms: 0.0,
-            duty_cycle: 0.0,
-            target_duty_cycle: 0.0,
-        }
-    }
-}
+// Relays, sensors and clock the control loop runs on
+struct Backend {
+    actuators: Box<dyn Actuators + Send>,
+    sensors: Vec<SensorReader>,
+    pushed_temperatures: HashMap<String, PushedTemperature>,
+    clock: Arc<dyn Clock>,
+    switches: Switches,
 
-#[derive(Message, Serialize)]
-#[rtype(result = "()")]
-struct FridgeStatusMessage {
-    pub status: FridgeStatus,
+    // Turns off both relays, safe to call from any thread
+    shutdown: Arc<dyn Fn() + Send + Sync>,
 }
 
 struct AppState {
     config: Arc<Mutex<Config>>,
-    pid: Arc<Mutex<Pid<f64>>>,
+    status: Arc<Mutex<FridgeStatusMessage>>,
+    broadcaster: Addr<Broadcaster>,
+    pushed_temperatures: HashMap<String, PushedTemperature>,
+    profile: Arc<Mutex<Option<ActiveProfile>>>,
+    profiles: Arc<Mutex<ProfileStore>>,
+    autotune: Arc<Mutex<Option<Autotune>>>,
+    manual_override: Arc<Mutex<Option<ManualOverride>>>,
+    alarms: Arc<Mutex<Alarms>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ApplyGains {
+    pub rule: TuningRule,
+}
+
+#[derive(Debug, Deserialize)]
+struct TemperatureUpdate {
+    pub temperature: f64,
 }
 
 // Display the UI
@@ -185,35 +363,455 @@ async fn index() -> actix_web::Result<NamedFile> {
 }
 
 // Update the configuration of the controller
+// Fields that are left out keep their current value
 async fn update_config(
     data: web::Data<AppState>,
-    config_update: web::Json<Config>,
+    config_update: web::Json<serde_json::Value>,
+) -> actix_web::Result<HttpResponse> {
+    let mut config = data.config.lock().unwrap();
+    let mut merged = serde_json::to_value(&*config)?;
+    merge_json(&mut merged, config_update.into_inner());
+    let mut update: Config = serde_json::from_value(merged).map_err(error::ErrorBadRequest)?;
+
+    // Sensors, relays and the door are only set up on startup, so changing them here would
+    // look like it worked without doing anything. Running a command set through the API
+    // would give away the controller, the redacted command is the one that is served.
+    let served_command = redacted(&config).alarms.command;
+    let restart_only: Vec<&str> = [
+        ("sensors", update.sensors != config.sensors),
+        ("relays", update.relays != config.relays),
+        ("door", update.door != config.door),
+        (
+            "alarms.command",
+            update.alarms.command != config.alarms.command
+                && update.alarms.command != served_command,
+        ),
+        (
+            "inside_sensor",
+            update.inside_sensor != config.inside_sensor,
+        ),
+        ("air_sensor", update.air_sensor != config.air_sensor),
+        (
+            "outside_sensor",
+            update.outside_sensor != config.outside_sensor,
+        ),
+    ]
+    .iter()
+    .filter(|(_, changed)| *changed)
+    .map(|(field, _)| *field)
+    .collect();
+    if !restart_only.is_empty() {
+        return Err(error::ErrorBadRequest(format!(
+            "{} can only be changed in config.json, followed by a restart",
+            restart_only.join(", ")
+        )));
+    }
+    update.alarms.command = config.alarms.command.clone();
+    update.validate().map_err(error::ErrorBadRequest)?;
+
+    // The controller picks up the changes on its next step
+    *config = update;
+    serde_json::to_writer(&File::create("config.json")?, &*config)?;
+    info!("Configuration updated {:?}", redacted(&config));
+    Ok(HttpResponse::Ok().json(redacted(&config)))
+}
+
+// The alarm command can hold phone numbers or credentials, the API only shows that it is set
+fn redacted(config: &Config) -> Config {
+    let mut config = config.clone();
+    if let Some(command) = config.alarms.command.as_mut() {
+        *command = vec!["<redacted>".to_string()];
+    }
+    config
+}
+
+// Recursively replace the values in `target` by the ones in `update`
+fn merge_json(target: &mut serde_json::Value, update: serde_json::Value) {
+    match (target, update) {
+        (serde_json::Value::Object(target), serde_json::Value::Object(update)) => {
+            for (key, value) in update {
+                merge_json(target.entry(key).or_insert(serde_json::Value::Null), value);
+            }
+        }
+        (target, update) => *target = update,
+    }
+}
+
+// The active profile and its position
+#[get("/api/profile")]
+async fn get_profile(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+    let profile = data.profile.lock().unwrap().clone();
+    Ok(HttpResponse::Ok().json(profile))
+}
+
+// Start a profile, replacing the active one
+async fn start_profile(
+    data: web::Data<AppState>,
+    profile: web::Json<Profile>,
 ) -> actix_web::Result<HttpResponse> {
+    profile.validate().map_err(error::ErrorBadRequest)?;
+    let target_temp = data.status.lock().unwrap().status.target_temp;
+    let active = ActiveProfile::start(None, profile.into_inner(), target_temp);
+    replace_active_profile(&data, active)
+}
+
+// Stop the active profile, the configured target temperature is used again
+async fn stop_profile(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+    let mut profile = data.profile.lock().unwrap();
+    if let Some(active) = profile.take() {
+        info!("Stopped profile {}", active.profile.name);
+    }
+    write_active_profile(&profile_path(), None).map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(&*profile))
+}
+
+// All stored profiles
+async fn list_profiles(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(data.profiles.lock().unwrap().list()))
+}
+
+// Store a new profile
+async fn create_profile(
+    data: web::Data<AppState>,
+    profile: web::Json<Profile>,
+) -> actix_web::Result<HttpResponse> {
+    profile.validate().map_err(error::ErrorBadRequest)?;
+    let mut profiles = data.profiles.lock().unwrap();
+    let id = profiles.insert(profile.clone());
+    profiles
+        .write(&profiles_path())
+        .map_err(error::ErrorInternalServerError)?;
+    info!("Created profile {} with id {}", profile.name, id);
+    Ok(HttpResponse::Created().json(StoredProfile {
+        id,
+        profile: profile.into_inner(),
+    }))
+}
+
+async fn get_stored_profile(
+    data: web::Data<AppState>,
+    id: web::Path<u64>,
+) -> actix_web::Result<HttpResponse> {
+    let profiles = data.profiles.lock().unwrap();
+    let profile = profiles
+        .get(*id)
+        .ok_or_else(|| error::ErrorNotFound("Unknown profile"))?;
+    Ok(HttpResponse::Ok().json(StoredProfile {
+        id: *id,
+        profile: profile.clone(),
+    }))
+}
+
+// Replace a stored profile, a running copy of it is not changed
+async fn update_profile(
+    data: web::Data<AppState>,
+    id: web::Path<u64>,
+    profile: web::Json<Profile>,
+) -> actix_web::Result<HttpResponse> {
+    profile.validate().map_err(error::ErrorBadRequest)?;
+    let mut profiles = data.profiles.lock().unwrap();
+    if !profiles.update(*id, profile.clone()) {
+        return Err(error::ErrorNotFound("Unknown profile"));
+    }
+    profiles
+        .write(&profiles_path())
+        .map_err(error::ErrorInternalServerError)?;
+    info!("Updated profile {} with id {}", profile.name, id);
+    Ok(HttpResponse::Ok().json(StoredProfile {
+        id: *id,
+        profile: profile.into_inner(),
+    }))
+}
+
+async fn delete_profile(
+    data: web::Data<AppState>,
+    id: web::Path<u64>,
+) -> actix_web::Result<HttpResponse> {
+    let mut profiles = data.profiles.lock().unwrap();
+    let profile = profiles
+        .remove(*id)
+        .ok_or_else(|| error::ErrorNotFound("Unknown profile"))?;
+    profiles
+        .write(&profiles_path())
+        .map_err(error::ErrorInternalServerError)?;
+    info!("Deleted profile {} with id {}", profile.name, id);
+    Ok(HttpResponse::Ok().json(StoredProfile { id: *id, profile }))
+}
+
+// Start a stored profile, replacing the active one
+async fn start_stored_profile(
+    data: web::Data<AppState>,
+    id: web::Path<u64>,
+) -> actix_web::Result<HttpResponse> {
+    let profile = data
+        .profiles
+        .lock()
+        .unwrap()
+        .get(*id)
+        .cloned()
+        .ok_or_else(|| error::ErrorNotFound("Unknown profile"))?;
+    let target_temp = data.status.lock().unwrap().status.target_temp;
+    let active = ActiveProfile::start(Some(*id), profile, target_temp);
+    replace_active_profile(&data, active)
+}
+
+// Save and activate a started profile. The file is written under the lock,
+// so a periodic save of the control loop can't overwrite it with the old profile.
+fn replace_active_profile(
+    data: &AppState,
+    active: ActiveProfile,
+) -> actix_web::Result<HttpResponse> {
+    let mut profile = data.profile.lock().unwrap();
+    write_active_profile(&profile_path(), Some(&active))
+        .map_err(error::ErrorInternalServerError)?;
+    *profile = Some(active);
+    Ok(HttpResponse::Ok().json(&*profile))
+}
+
+async fn pause_profile(
+    data: web::Data<AppState>,
+    id: web::Path<u64>,
+) -> actix_web::Result<HttpResponse> {
+    change_active_profile(&data, *id, ActiveProfile::pause)
+}
+
+async fn resume_profile(
+    data: web::Data<AppState>,
+    id: web::Path<u64>,
+) -> actix_web::Result<HttpResponse> {
+    change_active_profile(&data, *id, ActiveProfile::resume)
+}
+
+async fn skip_step(
+    data: web::Data<AppState>,
+    id: web::Path<u64>,
+) -> actix_web::Result<HttpResponse> {
+    change_active_profile(&data, *id, ActiveProfile::next_step)
+}
+
+// Change the active profile if it was started from the stored profile `id`
+fn change_active_profile(
+    data: &AppState,
+    id: u64,
+    change: impl FnOnce(&mut ActiveProfile),
+) -> actix_web::Result<HttpResponse> {
+    let mut profile = data.profile.lock().unwrap();
+    let active = profile
+        .as_mut()
+        .filter(|active| active.id == Some(id))
+        .ok_or_else(|| error::ErrorConflict("Profile is not running"))?;
+    change(active);
+    write_active_profile(&profile_path(), Some(active)).map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(&*active))
+}
+
+// Store the fermentation schedule of a BeerXML or BeerJSON recipe as a profile
+async fn import_stored_profile(
+    data: web::Data<AppState>,
+    recipe: String,
+) -> actix_web::Result<HttpResponse> {
+    let import = import_profile(&recipe).map_err(|e| error::ErrorBadRequest(format!("{:#}", e)))?;
+    let mut profiles = data.profiles.lock().unwrap();
+    let id = profiles.insert(import.profile.clone());
+    profiles
+        .write(&profiles_path())
+        .map_err(error::ErrorInternalServerError)?;
+    info!(
+        "Imported profile {} with id {}, ignored {:?}",
+        import.profile.name, id, import.ignored
+    );
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "id": id,
+        "profile": import.profile,
+        "ignored": import.ignored,
+    })))
+}
+
+// The running or last autotune experiment
+#[get("/api/autotune")]
+async fn get_autotune(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+    let autotune = data.autotune.lock().unwrap().clone();
+    Ok(HttpResponse::Ok().json(autotune))
+}
+
+// Start a relay experiment in the current operation mode
+async fn start_autotune(
+    data: web::Data<AppState>,
+    settings: web::Json<AutotuneSettings>,
+) -> actix_web::Result<HttpResponse> {
+    let mut autotune = data.autotune.lock().unwrap();
+    if autotune.as_ref().is_some_and(Autotune::is_running) {
+        return Err(error::ErrorConflict("Autotune is already running"));
+    }
+    if data.manual_override.lock().unwrap().is_some() {
+        return Err(error::ErrorConflict("A manual override is active"));
+    }
+    let configured_operation_mode = data.config.lock().unwrap().operation_mode;
+    let (target_temp, operation_mode) = {
+        let status = &data.status.lock().unwrap().status;
+        let operation_mode = match configured_operation_mode {
+            OperationMode::Auto => status.operation_mode,
+            operation_mode => operation_mode,
+        };
+        (status.target_temp, operation_mode)
+    };
+    let started = Autotune::start(
+        *settings,
+        settings.setpoint.unwrap_or(target_temp),
+        operation_mode,
+    )
+    .map_err(error::ErrorBadRequest)?;
+    *autotune = Some(started.clone());
+    Ok(HttpResponse::Ok().json(started))
+}
+
+// Stop a running experiment, the PID controller takes over again
+async fn stop_autotune(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+    let mut autotune = data.autotune.lock().unwrap();
+    if let Some(autotune) = autotune.as_mut() {
+        autotune.abort();
+    }
+    Ok(HttpResponse::Ok().json(&*autotune))
+}
+
+// Use the gains of a tuning rule from a finished experiment
+async fn apply_autotune(
+    data: web::Data<AppState>,
+    apply: web::Json<ApplyGains>,
+) -> actix_web::Result<HttpResponse> {
+    let gains = match data.autotune.lock().unwrap().as_ref().map(|a| &a.state) {
+        Some(AutotuneState::Done { result }) => result.gains[&apply.rule],
+        _ => return Err(error::ErrorConflict("No finished autotune")),
+    };
+    let mut config = data.config.lock().unwrap();
     let update = Config {
-        operation_mode: config_update.operation_mode,
-        target_temp: config_update.target_temp,
-        p: config_update.p,
-        i: config_update.i,
-        d: config_update.d,
+        p: gains.p,
+        i: gains.i,
+        d: gains.d,
+        ..config.clone()
     };
-    let mut temp = data.config.lock().unwrap();
-    temp.target_temp = update.target_temp;
-    let mut pid = data.pid.lock().unwrap();
-    pid.kp = update.p;
-    pid.ki = update.i;
-    pid.kd = update.d;
-    pid.reset_integral_term();
-    serde_json::to_writer(&File::create("config.json")?, &update)?;
-    info!("Configuration updated {:?}", config_update);
-    Ok(HttpResponse::Ok().json(*config_update))
+    // An experiment that measured nonsense does not get into config.json
+    update.validate().map_err(error::ErrorConflict)?;
+    *config = update;
+    serde_json::to_writer(&File::create("config.json")?, &*config)?;
+    info!("Applied {:?} gains {:?}", apply.rule, gains);
+    Ok(HttpResponse::Ok().json(redacted(&config)))
+}
+
+// The active manual override
+#[get("/api/override")]
+async fn get_override(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+    let manual_override = *data.manual_override.lock().unwrap();
+    Ok(HttpResponse::Ok().json(manual_override))
+}
+
+// Force the compressor or heater on or off for a limited time,
+// replaces a previous override
+async fn start_override(
+    data: web::Data<AppState>,
+    manual_override: web::Json<ManualOverride>,
+) -> actix_web::Result<HttpResponse> {
+    let mut manual_override = manual_override.into_inner();
+    manual_override.validate().map_err(error::ErrorBadRequest)?;
+    if data
+        .autotune
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(Autotune::is_running)
+    {
+        return Err(error::ErrorConflict("Autotune is running"));
+    }
+    manual_override.remaining_ms = manual_override.duration_ms;
+    info!("Override requested {:?}", manual_override);
+    *data.manual_override.lock().unwrap() = Some(manual_override);
+    Ok(HttpResponse::Ok().json(manual_override))
+}
+
+// End the override, the controller takes over again
+async fn stop_override(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+    let manual_override = data.manual_override.lock().unwrap().take();
+    if manual_override.is_some() {
+        info!("Override cancelled");
+    }
+    Ok(HttpResponse::Ok().json(manual_override))
+}
+
+// State of the alarm of every rule
+#[get("/api/alarms")]
+async fn get_alarms(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+    let alarms = data.alarms.lock().unwrap();
+    Ok(HttpResponse::Ok().json(alarms.alarms()))
+}
+
+// Stop the notifications of an active alarm until it is raised again
+async fn acknowledge_alarm(
+    data: web::Data<AppState>,
+    name: web::Path<String>,
+) -> actix_web::Result<HttpResponse> {
+    let mut alarms = data.alarms.lock().unwrap();
+    let alarm = alarms
+        .get_mut(name.as_str())
+        .ok_or_else(|| error::ErrorNotFound("Unknown alarm"))?;
+    if !alarm.acknowledge() {
+        return Err(error::ErrorConflict("Alarm is not active"));
+    }
+    Ok(HttpResponse::Ok().json(&*alarm))
+}
+
+// Push a temperature to a sensor of type Http
+async fn push_temperature(
+    data: web::Data<AppState>,
+    name: web::Path<String>,
+    update: web::Json<TemperatureUpdate>,
+) -> actix_web::Result<HttpResponse> {
+    let pushed = data
+        .pushed_temperatures
+        .get(name.as_str())
+        .ok_or_else(|| error::ErrorNotFound("Unknown sensor"))?;
+    *pushed.lock().unwrap() = Some((update.temperature, Instant::now()));
+    Ok(HttpResponse::Ok().json(update.temperature))
 }
 
 #[get("/api/config")]
 async fn get_config(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
-    let config = data.config.lock().unwrap().clone();
+    let config = redacted(&data.config.lock().unwrap());
     Ok(HttpResponse::Ok().json(config))
 }
 
+// Latest status of the controller, updated every control tick
+#[get("/api/status")]
+async fn get_status(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+    let status = data.status.lock().unwrap().clone();
+    Ok(HttpResponse::Ok().json(status))
+}
+
+// Stream status messages as Server-Sent Events on every tick and transition
+#[get("/api/stream")]
+async fn stream(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+    let rx = data
+        .broadcaster
+        .send(Subscribe)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .streaming(rx.map(Ok::<_, Error>)))
+}
+
+// Healthy as long as the control loop keeps ticking
+#[get("/api/health")]
+async fn health(data: web::Data<AppState>) -> actix_web::Result<HttpResponse> {
+    let timestamp = data.status.lock().unwrap().timestamp;
+    let age_ms = (Utc::now() - timestamp).num_milliseconds();
+    if age_ms > MAXIMUM_TICK_AGE_MS {
+        return Ok(HttpResponse::ServiceUnavailable()
+            .json(format!("Control loop has not ticked for {} ms", age_ms)));
+    }
+    Ok(HttpResponse::Ok().json("Healthy"))
+}
+
 #[get("/metrics")]
 async fn get_metrics() -> actix_web::Result<HttpResponse> {
     let mut buffer = Vec::new();
@@ -221,9 +819,8 @@ async fn get_metrics() -> actix_web::Result<HttpResponse> {
     let metric_families = prometheus::gather();
     encoder
         .encode(&metric_families, &mut buffer)
-        .map_err(|e| error::ErrorInternalServerError(e))?;
-    let output =
-        String::from_utf8(buffer.clone()).map_err(|e| error::ErrorInternalServerError(e))?;
+        .map_err(error::ErrorInternalServerError)?;
+    let output = String::from_utf8(buffer).map_err(error::ErrorInternalServerError)?;
     Ok(HttpResponse::Ok().body(output))
 }
 
@@ -237,51 +834,53 @@ async fn validator(req: ServiceRequest, auth: BearerAuth) -> Result<ServiceReque
     Err(error::ErrorUnauthorized("Not authorized"))
 }
 
-// Enable the compressor and log the event and update the FridgeStatus
-fn enable_compressor(compressor: &Pin, status: &mut FridgeStatus) -> Result<()> {
-    info!("Enabling compressor!");
-    compressor.set_value(1)?;
-    status.mode = Mode::Cooling;
-    status.mode_ms = 0.0;
-    Ok(())
-}
-
-// Disable the compressor and log the event and update the FridgeStatus
-fn disable_compressor(compressor: &Pin, status: &mut FridgeStatus) -> Result<()> {
-    info!("Disabling compressor");
-    compressor.set_value(0)?;
-    status.mode = Mode::Idle;
-    status.mode_ms = 0.0;
-    Ok(())
-}
-
-// Enable the heater and log the event and update the FridgeStatus
-fn enable_heater(heater: &Pin, status: &mut FridgeStatus) -> Result<()> {
-    info!("Enabling heater!");
-    heater.set_value(1)?;
-    status.mode = Mode::Heating;
-    status.mode_ms = 0.0;
-    Ok(())
-}
-
-// Disable the heater and log the event and update the FridgeStatus
-fn disable_heater(heater: &Pin, status: &mut FridgeStatus) -> Result<()> {
-    info!("Disabling heater");
-    heater.set_value(0)?;
-    status.mode = Mode::Idle;
-    status.mode_ms = 0.0;
-    Ok(())
-}
-
 // Write metrics to the Prometheus collectors
-fn write_metrics(status: &FridgeStatus, config: &Config) {
+fn write_metrics(status: &FridgeStatus, config: &Config, alarms: &[AlarmStatus]) {
     INSIDE_TEMP_CELCIUS.set(status.inside_temp);
     OUTSIDE_TEMP_CELCIUS.set(status.outside_temp);
-    TARGET_TEMP_CELCIUS.set(config.target_temp);
+    for (name, temperature) in &status.temperatures {
+        SENSOR_TEMP_CELCIUS
+            .with_label_values(&[name])
+            .set(*temperature);
+    }
+    TARGET_TEMP_CELCIUS.set(status.target_temp);
     PID_CORRECTION.set(status.correction);
     PID_P.set(config.p);
     PID_I.set(config.i);
     PID_D.set(config.d);
+    // Drop the terms of a loop that is no longer used
+    PID_TERM.reset();
+    for (name, terms) in &status.pid_terms {
+        for (term, value) in [
+            ("p", terms.p),
+            ("i", terms.i),
+            ("d", terms.d),
+            ("output", terms.output),
+        ] {
+            PID_TERM.with_label_values(&[name, term]).set(value);
+        }
+    }
+    AIR_SETPOINT_CELCIUS.set(status.air_setpoint.unwrap_or(f64::NAN));
+    DOOR_OPEN.set(if status.door.is_some_and(|door| door.open) {
+        1.0
+    } else {
+        0.0
+    });
+    for (relay, feedback) in &status.relay_feedback {
+        RELAY_FAULT
+            .with_label_values(&[relay])
+            .set(if feedback.fault.is_some() { 1.0 } else { 0.0 });
+    }
+    // Drop the faults that cleared
+    FAULT.reset();
+    for fault in &status.faults {
+        FAULT.with_label_values(&[&fault.name()]).set(1.0);
+    }
+    for alarm in alarms {
+        ALARM
+            .with_label_values(&[&alarm.name])
+            .set(if alarm.active { 1.0 } else { 0.0 });
+    }
     match status.mode {
         Mode::Cooling => {
             COMPRESSOR.set(1.0);
@@ -296,10 +895,26 @@ fn write_metrics(status: &FridgeStatus, config: &Config) {
     }
 }
 
+// The active profile is stored next to config.json
+fn profile_path() -> PathBuf {
+    PathBuf::from("profile.json")
+}
+
+// Exists while the controller runs, left behind when it stops without shutting down
+fn running_path() -> PathBuf {
+    PathBuf::from("running")
+}
+
+// Stored profiles that can be started
+fn profiles_path() -> PathBuf {
+    PathBuf::from("profiles.json")
+}
+
 fn read_config() -> Result<Config> {
     let file = File::open("config.json");
     if let Ok(f) = file {
-        let config = serde_json::from_reader(BufReader::new(f))?;
+        let config: Config = serde_json::from_reader(BufReader::new(f))?;
+        config.validate().context("invalid config.json")?;
         return Ok(config);
     }
     let config = Config::default();
@@ -307,199 +922,559 @@ fn read_config() -> Result<Config> {
     Ok(config)
 }
 
-#[actix_web::main]
-async fn main() -> anyhow::Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
-
-    // Temperature probes
+// The two One-Wire sensors from the INSIDE_SENSOR and OUTSIDE_SENSOR environment variables
+fn env_sensors() -> Vec<SensorConfig> {
     let outside_sensor_path = env::var("OUTSIDE_SENSOR").expect("OUTSIDE_SENSOR path not set");
     let inside_sensor_path = env::var("INSIDE_SENSOR").expect("INSIDE_SENSOR path not set");
+    vec![
+        SensorConfig::new(
+            "inside",
+            SensorKind::W1 {
+                path: inside_sensor_path,
+            },
+        ),
+        SensorConfig::new(
+            "outside",
+            SensorKind::W1 {
+                path: outside_sensor_path,
+            },
+        ),
+    ]
+}
+
+// Read all sensors, returns the temperatures and the stale sensors by name
+fn read_sensors(
+    sensors: &mut [SensorReader],
+    now: Instant,
+) -> (BTreeMap<String, f64>, Vec<String>) {
+    let mut temperatures = BTreeMap::new();
+    let mut stale_sensors = Vec::new();
+    for sensor in sensors.iter_mut() {
+        let failures = sensor.update(now);
+        SENSOR_READ_FAILURES
+            .with_label_values(&[sensor.name()])
+            .inc_by(failures as u64);
+        if let Some(temperature) = sensor.value() {
+            temperatures.insert(sensor.name().to_string(), temperature);
+        }
+        let stale = sensor.is_stale(now);
+        if stale {
+            stale_sensors.push(sensor.name().to_string());
+        }
+        SENSOR_STALE
+            .with_label_values(&[sensor.name()])
+            .set(if stale { 1.0 } else { 0.0 });
+    }
+    (temperatures, stale_sensors)
+}
+
+// Turn off both relays, used when the controller stops for whatever reason
+fn shutdown_relays(compressor: &OutputPin, heater: &OutputPin) {
+    for (name, pin) in [("compressor", compressor), ("heater", heater)] {
+        match pin.set_value(0) {
+            Ok(_) => info!("Turned off {}", name),
+            Err(e) => error!("Could not turn off {}: {}", name, e),
+        }
+    }
+}
+
+// Print the profile of a recipe file, it can be posted to /api/profiles
+fn import_recipe(path: &Path) -> Result<()> {
+    let recipe = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read {}", path.display()))?;
+    let import = import_profile(&recipe)?;
+    for field in &import.ignored {
+        warn!("Ignored {}", field);
+    }
+    println!("{}", serde_json::to_string_pretty(&import.profile)?);
+    Ok(())
+}
 
+fn parse_args() -> Result<Options> {
+    let mut options = Options {
+        simulate: false,
+        acceleration: 1.0,
+        ambient_temp: 20.0,
+        import: None,
+        fake_gpio: None,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "import" => {
+                options.import = Some(args.next().context("import needs a recipe file")?.into());
+            }
+            "--simulate" => options.simulate = true,
+            "--fake-gpio" => {
+                options.fake_gpio = Some(args.next().context("--fake-gpio needs a file")?.into());
+            }
+            "--acceleration" => {
+                options.acceleration = args
+                    .next()
+                    .context("--acceleration needs a value")?
+                    .parse()
+                    .context("invalid --acceleration")?;
+            }
+            "--ambient" => {
+                options.ambient_temp = args
+                    .next()
+                    .context("--ambient needs a value")?
+                    .parse()
+                    .context("invalid --ambient")?;
+            }
+            _ => anyhow::bail!(
+                "unknown argument {}, usage: frust [--fake-gpio LOG | --simulate [--acceleration N] [--ambient T]] | frust import RECIPE",
+                arg
+            ),
+        }
+    }
+    Ok(options)
+}
+
+// Compressor and heater on GPIO pins with the configured (or environment) sensors
+fn hardware_backend(config: &Config, options: &Options) -> Result<Backend> {
     // Set compressor and heater GPIO pins
-    let compressor = Pin::new(23);
-    compressor
-        .export()
-        .context("could not export compressor pin")?
-        .set_direction(Direction::Out)?
-        .set_value(0)?;
-    let heater = Pin::new(24);
-    heater
-        .export()
-        .context("could not export heater pin")?
-        .set_direction(Direction::Out)?
-        .set_value(0)?;
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let relays = match &options.fake_gpio {
+        Some(log) => config.relays.faked(log).open(&clock)?,
+        None => config.relays.open(&clock)?,
+    };
+    let (compressor, heater) = (relays.compressor.clone(), relays.heater.clone());
 
-    let config = read_config()?;
-    let pid = Pid::new(
-        config.p,
-        config.i,
-        config.d,
-        100.0,
-        100.0,
-        100.0,
-        100.0,
-        config.target_temp,
+    // Door switch and relay feedback, left out with fake pins that never change
+    let switches = match &options.fake_gpio {
+        Some(_) => Switches::default(),
+        None => {
+            let (compressor_feedback, heater_feedback) = config.relays.watch_feedback()?;
+            Switches {
+                door: config
+                    .door
+                    .as_ref()
+                    .map(|door| {
+                        Switch::watch(
+                            "door",
+                            &door.input,
+                            ["Door closed".to_string(), "Door opened".to_string()],
+                        )
+                    })
+                    .transpose()?,
+                compressor_feedback,
+                heater_feedback,
+            }
+        }
+    };
+
+    // Temperature probes
+    let sensor_configs = if config.sensors.is_empty() {
+        env_sensors()
+    } else {
+        config.sensors.clone()
+    };
+    let (sensors, pushed_temperatures) = build_sensors(&sensor_configs)?;
+    Ok(Backend {
+        actuators: Box::new(relays),
+        sensors,
+        pushed_temperatures,
+        clock,
+        switches,
+        shutdown: Arc::new(move || shutdown_relays(&compressor, &heater)),
+    })
+}
+
+// Thermal model of a fridge, optionally running faster than real time
+fn simulated_backend(options: &Options) -> Result<Backend> {
+    info!(
+        "Simulating a fridge in a {} °C room, {} times faster than real time",
+        options.ambient_temp, options.acceleration
     );
-    // Current status, will be updated by the control loop
-    let mut status = FridgeStatus::default();
+    let simulation = Simulation::new(options.ambient_temp, options.acceleration)?;
+    let shutdown = simulation.clone();
+    Ok(Backend {
+        actuators: Box::new(simulation.relays()),
+        sensors: simulation.sensors(),
+        pushed_temperatures: HashMap::new(),
+        clock: Arc::new(simulation.clock()),
+        switches: Switches::default(),
+        shutdown: Arc::new(move || shutdown.shutdown()),
+    })
+}
+
+#[actix_web::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+
+    let options = parse_args()?;
+    if let Some(path) = &options.import {
+        return import_recipe(path);
+    }
+    let config = read_config()?;
+    let backend = if options.simulate {
+        simulated_backend(&options)?
+    } else {
+        hardware_backend(&config, &options)?
+    };
+
+    // Never leave the relays on after a panic in any thread
+    let hook_shutdown = backend.shutdown.clone();
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        hook_shutdown();
+        default_hook(info);
+    }));
+
+    let air_sensor = config.cascade.map(|_| &config.air_sensor);
+    for name in std::iter::once(&config.inside_sensor)
+        .chain(&config.outside_sensor)
+        .chain(air_sensor)
+    {
+        if !backend.sensors.iter().any(|sensor| sensor.name() == name) {
+            anyhow::bail!("sensor {} is not configured", name);
+        }
+    }
+    let Backend {
+        mut actuators,
+        mut sensors,
+        pushed_temperatures,
+        clock,
+        switches,
+        shutdown,
+    } = backend;
+    let inside_sensor = config.inside_sensor.clone();
+    let outside_sensor = config.outside_sensor.clone();
+    let air_sensor = config.air_sensor.clone();
+
     let config = Arc::new(Mutex::new(config));
-    let pid = Arc::new(Mutex::new(pid));
+    let profile = Arc::new(Mutex::new(read_active_profile(&profile_path())?));
+    let profiles = Arc::new(Mutex::new(ProfileStore::read(&profiles_path())?));
+    let autotune = Arc::new(Mutex::new(None::<Autotune>));
+    let manual_override = Arc::new(Mutex::new(None::<ManualOverride>));
+    let restarted = alarms::mark_running(&running_path())?;
+    let alarms = Arc::new(Mutex::new(Alarms::new(restarted)));
+    let shared_status = Arc::new(Mutex::new(FridgeStatusMessage {
+        event: StatusEvent::Tick,
+        status: FridgeStatus::default(),
+        timestamp: Utc::now(),
+    }));
 
-    let control_pid = pid.clone();
     let control_config = config.clone();
-    let mut now = Instant::now();
-    thread::spawn(move || -> Result<()> {
-        loop {
-            let delta_ms: f64 = now.elapsed().as_millis() as f64;
-            now = Instant::now();
-            status.outside_temp =
-                read_temperature(&outside_sensor_path).expect("Could not read outside temperature");
-            status.inside_temp =
-                read_temperature(&inside_sensor_path).expect("Could not read inside temperature");
-
-            // Scoped block to quickly update configuration and release the lock
-            {
-                let mut control_pid = control_pid.lock().unwrap();
-                let correction = control_pid.next_control_output(status.inside_temp);
-                status.correction = correction.output;
-            }
-            status.target_duty_cycle = (status.correction / 100.0).abs() * DUTY_CYCLE_MS;
-
-            // This is one big messy state machine, I'll create ASCII art soon
-            // Basically, it works by having two operation modes cooling and heating.
-            // You can only switch between the two if a long period has passed
-            // to prevent any oscillation.
-            match control_config.lock().unwrap().operation_mode {
-                OperationMode::Cooling => {
-                    // Update duty cycle
-                    match status.mode {
-                        Mode::Idle => {
-                            // Update duty cycle
-                            status.duty_cycle = MIN_DUTY_CYCLE_MS.max(status.duty_cycle - delta_ms);
-
-                            // The 2 options are
-                            // Idle -> Idle
-                            // Idle -> Cooling
-
-                            if status.correction < 0.0 {
-                                // Check if we need to turn the cooler/heater on
-                                if status.duty_cycle < status.target_duty_cycle
-                                    && status.mode_ms >= MINIMUM_IDLE_TIME_COOLING_MS
-                                {
-                                    enable_compressor(&compressor, &mut status)?;
-                                }
-                                // We have cooled enough
-                            } else {
-                                // Possibly switch to heating
-                                if status.mode_ms > MINIMUM_COOLING_HEATING_SWITCH_TIME_MS {
-                                    info!("Switching to operation mode heating!");
-                                    status.operation_mode = OperationMode::Heating;
-                                    status.mode = Mode::Idle;
-                                    status.mode_ms = 0.0;
-                                }
-                            }
-                        }
-                        Mode::Cooling => {
-                            // Update duty cycle
-                            status.duty_cycle = DUTY_CYCLE_MS.min(status.duty_cycle + delta_ms);
-
-                            // The 2 options are
-                            // Cooling -> Idle
-                            // Cooling -> Cooling
-
-                            if status.mode_ms < MINIMUM_COOL_TIME_MS {
-                                // Do nothing because we keep cooling
-                            } else if status.duty_cycle > status.target_duty_cycle {
-                                disable_compressor(&compressor, &mut status)?;
+    let control_status = shared_status.clone();
+    let control_profile = profile.clone();
+    let control_autotune = autotune.clone();
+    let control_override = manual_override.clone();
+    let control_alarms = alarms.clone();
+    let broadcaster = Broadcaster::default().start();
+    let control_broadcaster = broadcaster.clone();
+    let running = Arc::new(AtomicBool::new(true));
+    let control_running = running.clone();
+    let mut controller = Controller::new();
+    let mut diagnostics = Diagnostics::default();
+    let control = thread::spawn(move || -> Result<()> {
+        let mut now = clock.now();
+        let mut profile_unsaved_ms = 0.0;
+        let mut fault_names = Vec::new();
+        while control_running.load(Ordering::SeqCst) {
+            let delta_ms = clock.now().duration_since(now).as_millis() as f64;
+            now = clock.now();
+            let (temperatures, stale_sensors) = read_sensors(&mut sensors, now);
+            let fresh = |name: &String| {
+                if stale_sensors.contains(name) {
+                    None
+                } else {
+                    temperatures.get(name).copied()
+                }
+            };
+            let inside_temp = fresh(&inside_sensor);
+
+            // Advance the active profile and save its position once in a while
+            let profile_status = {
+                let mut profile = control_profile.lock().unwrap();
+                match profile.as_mut() {
+                    Some(active) => {
+                        profile_unsaved_ms += delta_ms;
+                        let step_changed = active.advance(delta_ms, inside_temp);
+                        if step_changed || profile_unsaved_ms >= PROFILE_SAVE_INTERVAL_MS {
+                            if let Err(e) = write_active_profile(&profile_path(), Some(active)) {
+                                error!("Could not save the active profile: {:?}", e);
                             }
+                            profile_unsaved_ms = 0.0;
                         }
-                        _ => {
-                            panic!("Invalid mode for operation Cooling");
-                        }
+                        Some((active.setpoint(), active.status()))
                     }
+                    None => None,
                 }
-                OperationMode::Heating => {
-                    // Update duty cycle
-                    match status.mode {
-                        Mode::Idle => {
-                            // Update duty cycle
-                            status.duty_cycle = MIN_DUTY_CYCLE_MS.max(status.duty_cycle - delta_ms);
-
-                            // The 2 options are
-                            // Idle -> Idle
-                            // Idle -> Heating
-
-                            if status.correction > 0.0 {
-                                // Check if we need to turn the cooler/heater on
-                                if status.duty_cycle < status.target_duty_cycle
-                                    && status.mode_ms >= MINIMUM_IDLE_TIME_HEATING_MS
-                                {
-                                    enable_heater(&heater, &mut status)?;
-                                }
-                            } else {
-                                // Possibly switch to cooling
-                                if status.mode_ms > MINIMUM_HEATING_COOLING_SWITCH_TIME_MS {
-                                    info!("Switching to operation mode cooling!");
-                                    status.operation_mode = OperationMode::Cooling;
-                                    status.mode = Mode::Idle;
-                                    status.mode_ms = 0.0;
-                                }
-                            }
-                        }
-                        Mode::Heating => {
-                            // Update duty cycle
-                            status.duty_cycle = DUTY_CYCLE_MS.min(status.duty_cycle + delta_ms);
-
-                            // The 2 options are
-                            // Heating -> Idle
-                            // Heating -> Heating
-
-                            if status.mode_ms < MINIMUM_HEAT_TIME_MS {
-                                // Do nothing
-                            } else if status.duty_cycle > status.target_duty_cycle {
-                                disable_heater(&heater, &mut status)?;
-                            }
-                        }
-                        _ => {
-                            panic!("Invalid mode for operation Heating");
-                        }
+            };
+
+            // A running autotune experiment drives the relay around its own setpoint
+            let relay = {
+                let mut autotune = control_autotune.lock().unwrap();
+                match autotune.as_mut().filter(|autotune| autotune.is_running()) {
+                    Some(autotune) => {
+                        let relay = autotune.update(inside_temp, delta_ms);
+                        Some((relay, autotune.setpoint, autotune.operation_mode))
                     }
+                    None => None,
                 }
-            }
+            };
 
-            info!("🍺 {:?} 🍺", status);
-            status.mode_ms += delta_ms;
+            // A manual override forces the relays until it expires
+            let active_override = {
+                let mut manual_override = control_override.lock().unwrap();
+                if let Some(active) = manual_override.as_mut() {
+                    if !active.advance(delta_ms) {
+                        info!("Override expired");
+                        *manual_override = None;
+                    }
+                }
+                *manual_override
+            };
+
+            let inputs = {
+                let config = control_config.lock().unwrap();
+                let mut inputs = Inputs {
+                    inside_temp,
+                    outside_temp: outside_sensor.as_ref().and_then(fresh),
+                    air_temp: fresh(&air_sensor),
+                    target_temp: profile_status
+                        .as_ref()
+                        .map_or(config.target_temp, |(setpoint, _)| *setpoint),
+                    operation_mode: config.operation_mode,
+                    relay: None,
+                    manual_override: active_override,
+                    door: switches.door.as_ref().map(|door| DoorInput {
+                        open: door.is_on(),
+                        left_open_ms: config
+                            .door
+                            .as_ref()
+                            .map_or(f64::INFINITY, |door| door.left_open_ms),
+                    }),
+                    relay_feedback: RelayFeedback {
+                        compressor: switches.compressor_feedback.as_ref().map(Switch::is_on),
+                        heater: switches.heater_feedback.as_ref().map(Switch::is_on),
+                        timeout_ms: config.relays.feedback_timeout_ms,
+                    },
+                    strategy: config.strategy.clone(),
+                    gains: Gains {
+                        p: config.p,
+                        i: config.i,
+                        d: config.d,
+                    },
+                    pid_limits: config.pid_limits,
+                    cascade: config.cascade,
+                    feed_forward: config.feed_forward,
+                    timing: config.timing,
+                };
+                if let Some((relay, setpoint, operation_mode)) = relay {
+                    inputs.relay = Some(relay);
+                    inputs.target_temp = setpoint;
+                    inputs.operation_mode = operation_mode;
+                }
+                inputs
+            };
 
-            // Write metrics for Prometheus
+            let previous = controller.status();
+            let previous_modes = (previous.mode, previous.operation_mode);
+            let previous_override = previous.manual_override.is_some();
+            let previous_door = previous.door.map(|door| door.open);
+            let outputs = controller.step(&inputs, delta_ms);
+            actuators.apply(&outputs)?;
+
+            let mut status = controller.status().clone();
+            status.temperatures = temperatures;
+            status.stale_sensors = stale_sensors;
+            status.profile = profile_status.map(|(_, profile)| profile);
+            let diagnostics_config = control_config.lock().unwrap().diagnostics;
+            status.faults =
+                diagnostics.update(&status, inputs.outside_temp, &diagnostics_config, delta_ms);
+            let names: Vec<String> = status.faults.iter().map(Fault::name).collect();
+            if names != fault_names {
+                fault_names = names;
+                control_broadcaster.do_send(FridgeStatusMessage {
+                    event: StatusEvent::Fault,
+                    status: status.clone(),
+                    timestamp: Utc::now(),
+                });
+            }
+            if status.door.map(|door| door.open) != previous_door {
+                control_broadcaster.do_send(FridgeStatusMessage {
+                    event: StatusEvent::Door,
+                    status: status.clone(),
+                    timestamp: Utc::now(),
+                });
+            }
+            if status.manual_override.is_some() != previous_override {
+                control_broadcaster.do_send(FridgeStatusMessage {
+                    event: StatusEvent::Override,
+                    status: status.clone(),
+                    timestamp: Utc::now(),
+                });
+            }
+            if (status.mode, status.operation_mode) != previous_modes {
+                control_broadcaster.do_send(FridgeStatusMessage {
+                    event: StatusEvent::Transition,
+                    status: status.clone(),
+                    timestamp: Utc::now(),
+                });
+            }
+            // The full status is large, it is only logged at debug level
+            info!(
+                "🍺 {:?} target {:.2} {:?} {:?} correction {:.1} 🍺",
+                status.temperatures,
+                status.target_temp,
+                status.operation_mode,
+                status.mode,
+                status.correction
+            );
+            debug!("{:?}", status);
+
+            // Check the alarm rules against the complete status
             {
                 let config = control_config.lock().unwrap();
-                write_metrics(&status, &config);
+                let mut alarms = control_alarms.lock().unwrap();
+                let changed = alarms.update(&status, &config.alarms, delta_ms);
+                status.alarms = alarms.active();
+                if changed {
+                    control_broadcaster.do_send(FridgeStatusMessage {
+                        event: StatusEvent::Alarm,
+                        status: status.clone(),
+                        timestamp: Utc::now(),
+                    });
+                }
+
+                // Write metrics for Prometheus
+                write_metrics(&status, &config, alarms.alarms());
             }
 
-            thread::sleep(Duration::from_millis(1000));
+            // Publish the status for the API and subscribers
+            let message = FridgeStatusMessage {
+                event: StatusEvent::Tick,
+                status,
+                timestamp: Utc::now(),
+            };
+            *control_status.lock().unwrap() = message.clone();
+            control_broadcaster.do_send(message);
+
+            clock.sleep(Duration::from_millis(1000));
         }
+
+        // Keep the exact position of the active profile for the next start
+        if let Some(active) = control_profile.lock().unwrap().as_ref() {
+            write_active_profile(&profile_path(), Some(active))?;
+        }
+        Ok(())
     });
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         let state = web::Data::new(AppState {
             config: config.clone(),
-            pid: pid.clone(),
+            status: shared_status.clone(),
+            broadcaster: broadcaster.clone(),
+            pushed_temperatures: pushed_temperatures.clone(),
+            profile: profile.clone(),
+            profiles: profiles.clone(),
+            autotune: autotune.clone(),
+            manual_override: manual_override.clone(),
+            alarms: alarms.clone(),
         });
 
         App::new()
             .app_data(state.clone())
             .service(index)
             .service(get_config)
+            .service(get_status)
+            .service(health)
+            .service(stream)
             .service(
                 web::resource("/api/config")
                     .route(web::post().to(update_config))
                     .wrap(HttpAuthentication::bearer(validator)),
             )
+            .service(get_autotune)
+            .service(
+                web::resource("/api/autotune")
+                    .route(web::post().to(start_autotune))
+                    .route(web::delete().to(stop_autotune))
+                    .wrap(HttpAuthentication::bearer(validator)),
+            )
+            .service(
+                web::resource("/api/autotune/apply")
+                    .route(web::post().to(apply_autotune))
+                    .wrap(HttpAuthentication::bearer(validator)),
+            )
+            .service(get_override)
+            .service(
+                web::resource("/api/override")
+                    .route(web::post().to(start_override))
+                    .route(web::delete().to(stop_override))
+                    .wrap(HttpAuthentication::bearer(validator)),
+            )
+            .service(get_alarms)
+            .service(
+                web::resource("/api/alarms/{name}/acknowledge")
+                    .route(web::post().to(acknowledge_alarm))
+                    .wrap(HttpAuthentication::bearer(validator)),
+            )
+            .service(get_profile)
+            .service(
+                web::resource("/api/profile")
+                    .route(web::post().to(start_profile))
+                    .route(web::delete().to(stop_profile))
+                    .wrap(HttpAuthentication::bearer(validator)),
+            )
+            .service(
+                web::scope("/api/profiles")
+                    .wrap(HttpAuthentication::bearer(validator))
+                    .route("", web::get().to(list_profiles))
+                    .route("", web::post().to(create_profile))
+                    .route("/import", web::post().to(import_stored_profile))
+                    .route("/{id}", web::get().to(get_stored_profile))
+                    .route("/{id}", web::put().to(update_profile))
+                    .route("/{id}", web::delete().to(delete_profile))
+                    .route("/{id}/start", web::post().to(start_stored_profile))
+                    .route("/{id}/pause", web::post().to(pause_profile))
+                    .route("/{id}/resume", web::post().to(resume_profile))
+                    .route("/{id}/skip-step", web::post().to(skip_step)),
+            )
+            .service(
+                web::resource("/api/sensors/{name}")
+                    .route(web::post().to(push_temperature))
+                    .wrap(HttpAuthentication::bearer(validator)),
+            )
             .service(get_metrics)
     })
     .bind("0.0.0.0:8080")?
-    .run()
-    .await?;
+    .run();
 
+    // Turn off the relays and stop the server when the control loop ends,
+    // a non-zero exit code lets systemd restart the controller
+    let supervised_server = server.clone();
+    let supervisor = thread::spawn(move || -> bool {
+        let failed = match control.join() {
+            Ok(Ok(())) => false,
+            Ok(Err(e)) => {
+                error!("Control loop failed: {:?}", e);
+                true
+            }
+            Err(_) => {
+                error!("Control loop panicked");
+                true
+            }
+        };
+        shutdown();
+        if failed {
+            // Stopping is initiated on send, no need to wait for it
+            drop(supervised_server.stop(false));
+        }
+        failed
+    });
+
+    // Returns on SIGINT, SIGTERM or when stopped by the supervisor
+    server.await?;
+    warn!("Server stopped, stopping control loop");
+    running.store(false, Ordering::SeqCst);
+    let failed = supervisor.join().unwrap_or(true);
+    if failed {
+        anyhow::bail!("control loop failed");
+    }
+    fs::remove_file(running_path()).context("could not remove the running file")?;
     Ok(())
 }
@@ -0,0 +1,284 @@
+//! PID controller with output and term limits, conditional integration
+//! anti-windup and bumpless gain changes.
+//! The controller is updated once per control tick, so the integral and
+//! derivative gains are per tick, not per second: a tick also reads the sensors.
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+/// Limits of the output and of the magnitude of every term
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct PidLimits {
+    pub output_min: f64,
+    pub output_max: f64,
+    pub p: f64,
+    pub i: f64,
+    pub d: f64,
+}
+
+impl Default for PidLimits {
+    fn default() -> PidLimits {
+        PidLimits {
+            output_min: -100.0,
+            output_max: 100.0,
+            p: 100.0,
+            i: 100.0,
+            d: 100.0,
+        }
+    }
+}
+
+impl PidLimits {
+    pub fn validate(&self) -> Result<()> {
+        for (name, limit) in [("p", self.p), ("i", self.i), ("d", self.d)] {
+            if !limit.is_finite() || limit < 0.0 {
+                bail!("limit of the {} term must be a positive number", name);
+            }
+        }
+        // Negative outputs ask for cooling and positive ones for heating
+        if !self.output_min.is_finite()
+            || !self.output_max.is_finite()
+            || self.output_min >= self.output_max
+            || self.output_min > 0.0
+            || self.output_max < 0.0
+        {
+            bail!("output_min must be a number up to 0 and below output_max");
+        }
+        Ok(())
+    }
+}
+
+/// Directions in which the actuators can follow the output right now.
+/// The integral does not grow in a direction the actuators can't act on,
+/// for example while the compressor waits for its minimum idle time.
+#[derive(Debug, Copy, Clone)]
+pub struct Authority {
+    pub decrease: bool,
+    pub increase: bool,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Gains {
+    pub p: f64,
+    pub i: f64,
+    pub d: f64,
+}
+
+impl Gains {
+    pub fn validate(&self) -> Result<()> {
+        if !(self.p.is_finite() && self.i.is_finite() && self.d.is_finite()) {
+            bail!("gains must be numbers");
+        }
+        Ok(())
+    }
+}
+
+/// Terms of a PID controller in the last step
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct PidTerms {
+    pub p: f64,
+    pub i: f64,
+    pub d: f64,
+    pub output: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Pid {
+    pub kp: f64,
+    pub ki: f64,
+    pub kd: f64,
+    pub limits: PidLimits,
+    pub setpoint: f64,
+
+    // Integral term, the sum of the error times the integral gain of every step
+    integral: f64,
+
+    // Measurement of the previous step, for the derivative on measurement
+    prev_measurement: Option<f64>,
+}
+
+impl Pid {
+    pub fn new(kp: f64, ki: f64, kd: f64, limits: PidLimits, setpoint: f64) -> Pid {
+        Pid {
+            kp,
+            ki,
+            kd,
+            limits,
+            setpoint,
+            integral: 0.0,
+            prev_measurement: None,
+        }
+    }
+
+    /// Change the gains without a jump in the output.
+    /// The integral takes over the change of the proportional term,
+    /// without integral gain there is no integral to do so and it is cleared.
+    pub fn set_gains(&mut self, kp: f64, ki: f64, kd: f64) {
+        if (kp, ki, kd) == (self.kp, self.ki, self.kd) {
+            return;
+        }
+        match self.prev_measurement {
+            Some(measurement) if ki != 0.0 => {
+                let error = self.setpoint - measurement;
+                let before = self.proportional(error);
+                self.kp = kp;
+                let after = self.proportional(error);
+                self.integral = clamp(self.integral + before - after, self.limits.i);
+            }
+            _ => self.integral = 0.0,
+        }
+        self.kp = kp;
+        self.ki = ki;
+        self.kd = kd;
+    }
+
+    pub fn reset_integral_term(&mut self) {
+        self.integral = 0.0;
+    }
+
+    /// Follow the output of something else in control, so taking over does not
+    /// make the output jump. Without integral gain there is nothing to follow with.
+    pub fn track(&mut self, output: f64, measurement: f64) {
+        self.integral = if self.ki != 0.0 {
+            clamp(
+                output - self.proportional(self.setpoint - measurement),
+                self.limits.i,
+            )
+        } else {
+            0.0
+        };
+        self.prev_measurement = Some(measurement);
+    }
+
+    /// Next output for the measurement.
+    /// The integral only grows away from zero when the output is not saturated in that
+    /// direction and the actuators can act in it (conditional integration). It can always
+    /// shrink back to zero, so it unwinds after an overshoot.
+    pub fn next_control_output(&mut self, measurement: f64, authority: Authority) -> PidTerms {
+        let error = self.setpoint - measurement;
+        let p = self.proportional(error);
+        // Derivative on measurement, so a setpoint change does not kick the output
+        let d = self.prev_measurement.map_or(0.0, |prev_measurement| {
+            clamp(-self.kd * (measurement - prev_measurement), self.limits.d)
+        });
+        self.prev_measurement = Some(measurement);
+
+        // The limit might have been lowered since the last step
+        self.integral = clamp(self.integral, self.limits.i);
+        let integral = clamp(self.integral + self.ki * error, self.limits.i);
+        let unlimited = p + integral + d;
+        let can_increase = unlimited <= self.limits.output_max && authority.increase;
+        let can_decrease = unlimited >= self.limits.output_min && authority.decrease;
+        self.integral = if integral > self.integral && !can_increase {
+            // Only up to zero
+            integral.min(self.integral.max(0.0))
+        } else if integral < self.integral && !can_decrease {
+            integral.max(self.integral.min(0.0))
+        } else {
+            integral
+        };
+
+        PidTerms {
+            p,
+            i: self.integral,
+            d,
+            output: (p + self.integral + d).clamp(self.limits.output_min, self.limits.output_max),
+        }
+    }
+
+    fn proportional(&self, error: f64) -> f64 {
+        clamp(self.kp * error, self.limits.p)
+    }
+}
+
+// Limit the magnitude of a term
+fn clamp(value: f64, limit: f64) -> f64 {
+    value.clamp(-limit, limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOTH: Authority = Authority {
+        decrease: true,
+        increase: true,
+    };
+
+    #[test]
+    fn gain_change_does_not_step_the_output() {
+        let mut pid = Pid::new(5.0, 0.1, 2.0, PidLimits::default(), 18.0);
+        for _ in 0..10 {
+            pid.next_control_output(20.0, BOTH);
+        }
+        let mut unchanged = pid.clone();
+        pid.set_gains(10.0, 0.1, 4.0);
+        let changed = pid.next_control_output(20.0, BOTH);
+        let expected = unchanged.next_control_output(20.0, BOTH);
+        assert!((changed.output - expected.output).abs() < 1e-9);
+        assert_eq!(changed.p, -20.0);
+    }
+
+    #[test]
+    fn integral_does_not_grow_without_authority() {
+        let mut pid = Pid::new(5.0, 0.1, 0.0, PidLimits::default(), 18.0);
+        // The compressor waits for its minimum idle time
+        let waiting = Authority {
+            decrease: false,
+            increase: true,
+        };
+        for _ in 0..100 {
+            assert_eq!(pid.next_control_output(20.0, waiting).i, 0.0);
+        }
+        let terms = pid.next_control_output(20.0, BOTH);
+        assert!((terms.i + 0.2).abs() < 1e-9);
+    }
+
+    #[test]
+    fn integral_unwinds_without_authority() {
+        let mut pid = Pid::new(5.0, 0.1, 0.0, PidLimits::default(), 18.0);
+        for _ in 0..100 {
+            pid.next_control_output(17.0, BOTH);
+        }
+        assert!((pid.integral - 10.0).abs() < 1e-9);
+        // Overshoot while the heater runs, the compressor can't act yet
+        let heating = Authority {
+            decrease: false,
+            increase: true,
+        };
+        let terms = pid.next_control_output(20.0, heating);
+        assert!((terms.i - 9.8).abs() < 1e-9);
+        // It stops at zero instead of winding up towards cooling
+        for _ in 0..100 {
+            pid.next_control_output(20.0, heating);
+        }
+        assert_eq!(pid.integral, 0.0);
+    }
+
+    #[test]
+    fn output_and_terms_stay_within_their_limits() {
+        let limits = PidLimits {
+            output_min: -50.0,
+            output_max: 30.0,
+            p: 40.0,
+            i: 20.0,
+            d: 10.0,
+        };
+        let mut pid = Pid::new(50.0, 5.0, 50.0, limits, 18.0);
+        for measurement in [30.0, 10.0, 30.0, 5.0, 18.0, 40.0, 0.0, 0.0, 0.0] {
+            let terms = pid.next_control_output(measurement, BOTH);
+            assert!(terms.p.abs() <= limits.p);
+            assert!(terms.i.abs() <= limits.i);
+            assert!(terms.d.abs() <= limits.d);
+            assert!(terms.output >= limits.output_min && terms.output <= limits.output_max);
+        }
+        // Every term at its limit
+        let terms = pid.next_control_output(-100.0, BOTH);
+        assert_eq!(terms.p, limits.p);
+        assert_eq!(terms.d, limits.d);
+        assert_eq!(terms.output, limits.output_max);
+        // Lowering a limit also limits the integral collected before
+        pid.limits.i = 5.0;
+        assert!(pid.next_control_output(-100.0, BOTH).i <= 5.0);
+    }
+}
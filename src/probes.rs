@@ -1,20 +1,577 @@
-use std::fs;
+//! Temperature sensors. Every backend implements `TemperatureSensor`
+//! so the control loop can read any number of named sensors.
+use std::{
+    collections::{HashMap, VecDeque},
+    fs, io,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
-use anyhow::{Context, Result};
+use lazy_static::lazy_static;
+use log::warn;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
-/// Read temperatur from a One-Wire file to a decimal Celcius
-/// For example 18.5
-pub fn read_temperature(path: &str) -> Result<f64> {
-    let contents = fs::read_to_string(path)?;
-    let re = Regex::new(r"(?m)t=([0-9]+)$")?;
-    let caps = re
-        .captures(&contents)
-        .context("capture failure for temperature")?;
-    Ok(caps
-        .get(1)
-        .context("could not read temperature")?
-        .as_str()
-        .parse::<f64>()?
-        / 1000.0)
+// DS18B20 power-on reset value, reported when the conversion did not complete
+const POWER_ON_RESET_MILLI_CELCIUS: i64 = 85000;
+
+lazy_static! {
+    static ref CRC_RE: Regex = Regex::new(r"(?m)crc=([0-9a-fA-F]{2}) (YES|NO)$").unwrap();
+    static ref TEMPERATURE_RE: Regex = Regex::new(r"(?m)t=(-?[0-9]+)$").unwrap();
+}
+
+#[derive(Error, Debug)]
+pub enum ProbeError {
+    #[error("could not read sensor file: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("no crc line in sensor output")]
+    MissingCrc,
+
+    #[error("crc check failed (crc={0})")]
+    CrcMismatch(String),
+
+    #[error("no temperature in sensor output")]
+    MissingTemperature,
+
+    #[error("invalid temperature {0}")]
+    InvalidTemperature(String),
+
+    #[error("sensor reported the power-on reset value")]
+    PowerOnReset,
+
+    #[error("no temperature has been pushed yet")]
+    NoReading,
+}
+
+/// A named source of temperatures in Celcius
+pub trait TemperatureSensor: Send {
+    fn name(&self) -> &str;
+
+    fn read(&mut self) -> Result<f64, ProbeError>;
+}
+
+/// Sensor backend and its settings as stored in `config.json`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+pub enum SensorKind {
+    // One-Wire sysfs `w1_slave` file
+    W1 { path: String },
+    // Linux hwmon or iio file in milli degrees, e.g. `temp1_input`
+    Hwmon { path: String },
+    // Fixed temperature
+    Static { value: f64 },
+    // File with a temperature per line, one line is read per tick
+    Sequence { path: String },
+    // Temperature pushed to `POST /api/sensors/{name}`
+    Http,
+}
+
+/// Filter applied to the successful reads of a sensor
+#[derive(Debug, Copy, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+pub enum Filter {
+    #[default]
+    None,
+    // Median of the last `window` samples
+    Median {
+        window: usize,
+    },
+    // Exponential moving average, `alpha` is the weight of a new sample
+    Ema {
+        alpha: f64,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SensorConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: SensorKind,
+
+    // Extra reads within the same tick when a read fails
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+
+    #[serde(default)]
+    pub filter: Filter,
+
+    // Time without a successful read before the sensor is considered stale (ms)
+    #[serde(default = "default_stale_ms")]
+    pub stale_ms: f64,
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+fn default_stale_ms() -> f64 {
+    60000.0
+}
+
+impl SensorConfig {
+    pub fn new(name: &str, kind: SensorKind) -> SensorConfig {
+        SensorConfig {
+            name: name.to_string(),
+            kind,
+            retries: default_retries(),
+            filter: Filter::default(),
+            stale_ms: default_stale_ms(),
+        }
+    }
+}
+
+/// Latest temperature pushed to an HTTP sensor and when it was pushed
+pub type PushedTemperature = Arc<Mutex<Option<(f64, Instant)>>>;
+
+/// Create the sensors from the configuration.
+/// Also returns the handles to push temperatures to the HTTP sensors by name.
+pub fn build_sensors(
+    configs: &[SensorConfig],
+) -> anyhow::Result<(Vec<SensorReader>, HashMap<String, PushedTemperature>)> {
+    let mut sensors = Vec::new();
+    let mut pushed = HashMap::new();
+    for config in configs {
+        let name = config.name.clone();
+        let sensor: Box<dyn TemperatureSensor> = match &config.kind {
+            SensorKind::W1 { path } => Box::new(W1Sensor {
+                name,
+                path: path.clone(),
+            }),
+            SensorKind::Hwmon { path } => Box::new(HwmonSensor {
+                name,
+                path: path.clone(),
+            }),
+            SensorKind::Static { value } => {
+                if !value.is_finite() {
+                    anyhow::bail!("value of sensor {} must be a number", config.name);
+                }
+                Box::new(StaticSensor {
+                    name,
+                    value: *value,
+                })
+            }
+            SensorKind::Sequence { path } => Box::new(SequenceSensor::open(name, path)?),
+            SensorKind::Http => {
+                let value = PushedTemperature::default();
+                pushed.insert(name.clone(), value.clone());
+                Box::new(HttpSensor {
+                    name,
+                    value,
+                    stale_ms: config.stale_ms,
+                })
+            }
+        };
+        if !config.stale_ms.is_finite() || config.stale_ms < 0.0 {
+            anyhow::bail!("stale_ms of sensor {} must be at least 0", config.name);
+        }
+        if let Filter::Median { window: 0 } = config.filter {
+            anyhow::bail!("median filter of sensor {} needs a window", config.name);
+        }
+        if let Filter::Ema { alpha } = config.filter {
+            if !(alpha > 0.0 && alpha <= 1.0) {
+                anyhow::bail!("ema alpha of sensor {} must be in (0, 1]", config.name);
+            }
+        }
+        sensors.push(SensorReader::new(sensor, config));
+    }
+    Ok((sensors, pushed))
+}
+
+/// Reads a sensor with retries and filtering,
+/// and keeps track of when the sensor was last read successfully.
+pub struct SensorReader {
+    sensor: Box<dyn TemperatureSensor>,
+    retries: u32,
+    filter: Filter,
+    stale_ms: f64,
+    samples: VecDeque<f64>,
+    value: Option<f64>,
+    last_read: Option<Instant>,
+}
+
+impl SensorReader {
+    pub fn new(sensor: Box<dyn TemperatureSensor>, config: &SensorConfig) -> SensorReader {
+        SensorReader {
+            sensor,
+            retries: config.retries,
+            filter: config.filter,
+            stale_ms: config.stale_ms,
+            samples: VecDeque::new(),
+            value: None,
+            last_read: None,
+        }
+    }
+
+    /// Reader with the default retries, no filter and the default stale timeout
+    pub fn with_defaults(sensor: Box<dyn TemperatureSensor>) -> SensorReader {
+        SensorReader {
+            sensor,
+            retries: default_retries(),
+            filter: Filter::default(),
+            stale_ms: default_stale_ms(),
+            samples: VecDeque::new(),
+            value: None,
+            last_read: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.sensor.name()
+    }
+
+    /// Read the sensor, retrying failed reads.
+    /// Returns the number of failed reads.
+    pub fn update(&mut self, now: Instant) -> u32 {
+        let mut failures = 0;
+        while failures <= self.retries {
+            match self.sensor.read() {
+                Ok(temperature) if !temperature.is_finite() => {
+                    warn!(
+                        "Could not read {} temperature: {}",
+                        self.name(),
+                        ProbeError::InvalidTemperature(temperature.to_string())
+                    );
+                    failures += 1;
+                }
+                Ok(temperature) => {
+                    self.add_sample(temperature);
+                    self.last_read = Some(now);
+                    break;
+                }
+                // Nothing to retry until a temperature is pushed, the sensor goes stale instead
+                Err(ProbeError::NoReading) => break,
+                Err(e) => {
+                    warn!("Could not read {} temperature: {}", self.name(), e);
+                    failures += 1;
+                }
+            }
+        }
+        failures
+    }
+
+    /// Filtered temperature, or the last one if the latest reads failed.
+    /// None if the sensor has never been read successfully.
+    pub fn value(&self) -> Option<f64> {
+        self.value
+    }
+
+    /// Whether the sensor has not been read successfully for too long
+    pub fn is_stale(&self, now: Instant) -> bool {
+        match self.last_read {
+            Some(last_read) => now.duration_since(last_read).as_millis() as f64 > self.stale_ms,
+            None => true,
+        }
+    }
+
+    fn add_sample(&mut self, temperature: f64) {
+        self.value = Some(match self.filter {
+            Filter::None => temperature,
+            Filter::Median { window } => {
+                self.samples.push_back(temperature);
+                while self.samples.len() > window {
+                    self.samples.pop_front();
+                }
+                let mut sorted: Vec<f64> = self.samples.iter().copied().collect();
+                sorted.sort_by(f64::total_cmp);
+                let middle = sorted.len() / 2;
+                if sorted.len().is_multiple_of(2) {
+                    (sorted[middle - 1] + sorted[middle]) / 2.0
+                } else {
+                    sorted[middle]
+                }
+            }
+            Filter::Ema { alpha } => match self.value {
+                Some(value) => alpha * temperature + (1.0 - alpha) * value,
+                None => temperature,
+            },
+        });
+    }
+}
+
+/// DS18B20 One-Wire sensor exposed through sysfs
+pub struct W1Sensor {
+    name: String,
+    path: String,
+}
+
+impl TemperatureSensor for W1Sensor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&mut self) -> Result<f64, ProbeError> {
+        read_temperature(&self.path)
+    }
+}
+
+/// Sensor exposed through the hwmon or iio subsystem in milli degrees
+pub struct HwmonSensor {
+    name: String,
+    path: String,
+}
+
+impl TemperatureSensor for HwmonSensor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&mut self) -> Result<f64, ProbeError> {
+        let contents = fs::read_to_string(&self.path)?;
+        let milli_celcius = contents
+            .trim()
+            .parse::<i64>()
+            .map_err(|_| ProbeError::InvalidTemperature(contents.trim().to_string()))?;
+        Ok(milli_celcius as f64 / 1000.0)
+    }
+}
+
+/// Sensor that always reports the same temperature
+pub struct StaticSensor {
+    name: String,
+    value: f64,
+}
+
+impl TemperatureSensor for StaticSensor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&mut self) -> Result<f64, ProbeError> {
+        Ok(self.value)
+    }
+}
+
+/// Sensor that replays a scripted sequence of temperatures, one per read.
+/// The last temperature is repeated once the sequence is exhausted.
+pub struct SequenceSensor {
+    name: String,
+    values: Vec<f64>,
+    position: usize,
+}
+
+impl SequenceSensor {
+    fn open(name: String, path: &str) -> anyhow::Result<SequenceSensor> {
+        let values = fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::parse::<f64>)
+            .collect::<Result<Vec<_>, _>>()?;
+        if values.is_empty() {
+            anyhow::bail!("sequence {} for sensor {} is empty", path, name);
+        }
+        if let Some(value) = values.iter().find(|value| !value.is_finite()) {
+            anyhow::bail!("sequence {} for sensor {} contains {}", path, name, value);
+        }
+        Ok(SequenceSensor {
+            name,
+            values,
+            position: 0,
+        })
+    }
+}
+
+impl TemperatureSensor for SequenceSensor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&mut self) -> Result<f64, ProbeError> {
+        let value = self.values[self.position];
+        self.position = (self.position + 1).min(self.values.len() - 1);
+        Ok(value)
+    }
+}
+
+/// Sensor that reports the last temperature pushed over the API
+pub struct HttpSensor {
+    name: String,
+    value: PushedTemperature,
+
+    // A pushed temperature older than this is not read again, so the sensor goes stale
+    stale_ms: f64,
+}
+
+impl TemperatureSensor for HttpSensor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&mut self) -> Result<f64, ProbeError> {
+        match *self.value.lock().unwrap() {
+            Some((temperature, pushed)) if pushed.elapsed().as_millis() as f64 <= self.stale_ms => {
+                Ok(temperature)
+            }
+            _ => Err(ProbeError::NoReading),
+        }
+    }
+}
+
+/// Read temperature from a One-Wire file to a decimal Celcius
+/// For example 18.5 or -1.25
+///
+/// The w1_slave file contains two lines, the first ends with the CRC check
+/// and the second with the temperature in milli degrees:
+///
+/// ```text
+/// 72 01 4b 46 7f ff 0e 10 57 : crc=57 YES
+/// 72 01 4b 46 7f ff 0e 10 57 t=23125
+/// ```
+pub fn read_temperature(path: &str) -> Result<f64, ProbeError> {
+    parse_w1_slave(&fs::read_to_string(path)?)
+}
+
+fn parse_w1_slave(contents: &str) -> Result<f64, ProbeError> {
+    let crc = CRC_RE.captures(contents).ok_or(ProbeError::MissingCrc)?;
+    if &crc[2] != "YES" {
+        return Err(ProbeError::CrcMismatch(crc[1].to_string()));
+    }
+    let caps = TEMPERATURE_RE
+        .captures(contents)
+        .ok_or(ProbeError::MissingTemperature)?;
+    let milli_celcius = caps[1]
+        .parse::<i64>()
+        .map_err(|_| ProbeError::InvalidTemperature(caps[1].to_string()))?;
+    if milli_celcius == POWER_ON_RESET_MILLI_CELCIUS {
+        return Err(ProbeError::PowerOnReset);
+    }
+    Ok(milli_celcius as f64 / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn parses_negative_temperature() {
+        let contents = "6c fe 4b 46 7f ff 0c 10 5d : crc=5d YES\n\
+                        6c fe 4b 46 7f ff 0c 10 5d t=-1250\n";
+        assert_eq!(parse_w1_slave(contents).unwrap(), -1.25);
+    }
+
+    #[test]
+    fn rejects_failed_crc() {
+        let contents = "72 01 4b 46 7f ff 0e 10 57 : crc=57 NO\n\
+                        72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+        match parse_w1_slave(contents) {
+            Err(ProbeError::CrcMismatch(crc)) => assert_eq!(crc, "57"),
+            other => panic!("expected a crc mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_power_on_reset() {
+        let contents = "50 05 4b 46 7f ff 0c 10 1c : crc=1c YES\n\
+                        50 05 4b 46 7f ff 0c 10 1c t=85000\n";
+        assert!(matches!(
+            parse_w1_slave(contents),
+            Err(ProbeError::PowerOnReset)
+        ));
+    }
+
+    #[test]
+    fn rejects_missing_crc() {
+        let contents = "72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+        assert!(matches!(
+            parse_w1_slave(contents),
+            Err(ProbeError::MissingCrc)
+        ));
+    }
+
+    // Sensor that reports the given results in turn
+    struct Scripted(Vec<Result<f64, ProbeError>>);
+
+    impl TemperatureSensor for Scripted {
+        fn name(&self) -> &str {
+            "scripted"
+        }
+
+        fn read(&mut self) -> Result<f64, ProbeError> {
+            self.0.remove(0)
+        }
+    }
+
+    #[test]
+    fn does_not_retry_without_a_pushed_temperature() {
+        let (mut sensors, _) =
+            build_sensors(&[SensorConfig::new("http", SensorKind::Http)]).unwrap();
+        let now = Instant::now();
+        assert_eq!(sensors[0].update(now), 0);
+        assert_eq!(sensors[0].value(), None);
+        assert!(sensors[0].is_stale(now));
+    }
+
+    #[test]
+    fn goes_stale_when_pushes_stop() {
+        let (mut sensors, pushed) =
+            build_sensors(&[SensorConfig::new("http", SensorKind::Http)]).unwrap();
+        let now = Instant::now();
+        *pushed["http"].lock().unwrap() = Some((20.0, now));
+        sensors[0].update(now);
+        assert_eq!(sensors[0].value(), Some(20.0));
+        assert!(!sensors[0].is_stale(now));
+
+        // The pusher stopped a minute ago, the old temperature is not read again
+        let pushed_at = Instant::now()
+            .checked_sub(Duration::from_millis(60001))
+            .unwrap();
+        *pushed["http"].lock().unwrap() = Some((20.0, pushed_at));
+        let later = now + Duration::from_millis(60001);
+        assert_eq!(sensors[0].update(later), 0);
+        assert!(sensors[0].is_stale(later));
+    }
+
+    #[test]
+    fn rejects_invalid_stale_ms_and_alpha() {
+        for stale_ms in [f64::NAN, f64::INFINITY, -1.0] {
+            let config = SensorConfig {
+                stale_ms,
+                ..SensorConfig::new("http", SensorKind::Http)
+            };
+            assert!(build_sensors(&[config]).is_err());
+        }
+        let config = SensorConfig {
+            filter: Filter::Ema { alpha: f64::NAN },
+            ..SensorConfig::new("http", SensorKind::Http)
+        };
+        assert!(build_sensors(&[config]).is_err());
+    }
+
+    #[test]
+    fn skips_non_finite_temperatures() {
+        let mut reader = SensorReader::new(
+            Box::new(Scripted(vec![Ok(f64::NAN), Ok(f64::INFINITY), Ok(12.5)])),
+            &SensorConfig {
+                filter: Filter::Median { window: 3 },
+                ..SensorConfig::new("scripted", SensorKind::Http)
+            },
+        );
+        assert_eq!(reader.update(Instant::now()), 2);
+        assert_eq!(reader.value(), Some(12.5));
+    }
+
+    #[test]
+    fn rejects_non_finite_sequence() {
+        let path = std::env::temp_dir().join(format!("frust-sequence-{}", std::process::id()));
+        fs::write(&path, "18.0\nNaN\n").unwrap();
+        let config = SensorConfig::new(
+            "sequence",
+            SensorKind::Sequence {
+                path: path.to_string_lossy().to_string(),
+            },
+        );
+        let result = build_sensors(&[config]);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rejects_non_finite_static_value() {
+        let config = SensorConfig::new("static", SensorKind::Static { value: f64::NAN });
+        assert!(build_sensors(&[config]).is_err());
+    }
 }
@@ -0,0 +1,478 @@
+//! Temperature profiles, such as a fermentation schedule.
+//! A profile is an ordered list of steps, the control loop advances the
+//! active profile every tick and uses its setpoint as target temperature.
+use std::{collections::BTreeMap, fs::File, io::BufReader, path::Path};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+/// Condition on the inside temperature that ends a `HoldUntil` step
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+pub enum Condition {
+    // Inside temperature dropped to or below the temperature
+    Below { temperature: f64 },
+    // Inside temperature rose to or above the temperature
+    Above { temperature: f64 },
+    // Only ends when the step is skipped
+    Manual,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type")]
+pub enum Step {
+    // Keep the temperature for a duration
+    Hold {
+        temperature: f64,
+        duration_ms: f64,
+    },
+    // Linearly move from the previous setpoint to the temperature over a duration
+    Ramp {
+        temperature: f64,
+        duration_ms: f64,
+    },
+    // Keep the temperature until the condition is met
+    HoldUntil {
+        temperature: f64,
+        condition: Condition,
+    },
+}
+
+impl Step {
+    pub fn temperature(&self) -> f64 {
+        match self {
+            Step::Hold { temperature, .. }
+            | Step::Ramp { temperature, .. }
+            | Step::HoldUntil { temperature, .. } => *temperature,
+        }
+    }
+
+    /// Duration of the step, None if it ends on a condition
+    pub fn duration_ms(&self) -> Option<f64> {
+        match self {
+            Step::Hold { duration_ms, .. } | Step::Ramp { duration_ms, .. } => Some(*duration_ms),
+            Step::HoldUntil { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    pub steps: Vec<Step>,
+}
+
+impl Profile {
+    pub fn validate(&self) -> Result<()> {
+        if self.steps.is_empty() {
+            bail!("profile {} has no steps", self.name);
+        }
+        for (i, step) in self.steps.iter().enumerate() {
+            if !step.temperature().is_finite() {
+                bail!(
+                    "step {} of profile {} has an invalid temperature",
+                    i,
+                    self.name
+                );
+            }
+            if let Some(duration_ms) = step.duration_ms() {
+                if !duration_ms.is_finite() || duration_ms < 0.0 {
+                    bail!(
+                        "step {} of profile {} has an invalid duration",
+                        i,
+                        self.name
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Profile with the id it is stored under
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredProfile {
+    pub id: u64,
+    #[serde(flatten)]
+    pub profile: Profile,
+}
+
+/// Named profiles that can be started
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ProfileStore {
+    next_id: u64,
+    profiles: BTreeMap<u64, Profile>,
+}
+
+impl ProfileStore {
+    /// Read the stored profiles, empty if there is no file yet
+    pub fn read(path: &Path) -> Result<ProfileStore> {
+        match File::open(path) {
+            Ok(f) => serde_json::from_reader(BufReader::new(f))
+                .with_context(|| format!("invalid profiles file {}", path.display())),
+            Err(_) => Ok(ProfileStore::default()),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        serde_json::to_writer(&File::create(path)?, self)?;
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<StoredProfile> {
+        self.profiles
+            .iter()
+            .map(|(id, profile)| StoredProfile {
+                id: *id,
+                profile: profile.clone(),
+            })
+            .collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Profile> {
+        self.profiles.get(&id)
+    }
+
+    /// Store a new profile, returns its id
+    pub fn insert(&mut self, profile: Profile) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.profiles.insert(id, profile);
+        id
+    }
+
+    /// Replace a profile, returns false if there is no profile with the id
+    pub fn update(&mut self, id: u64, profile: Profile) -> bool {
+        match self.profiles.get_mut(&id) {
+            Some(stored) => {
+                *stored = profile;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<Profile> {
+        self.profiles.remove(&id)
+    }
+}
+
+/// A running profile and its position
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ActiveProfile {
+    // Id of the stored profile, None if it was started directly
+    #[serde(default)]
+    pub id: Option<u64>,
+
+    pub profile: Profile,
+
+    // Index of the current step
+    pub step: usize,
+
+    // Time spent in the current step (ms)
+    pub step_elapsed_ms: f64,
+
+    // Setpoint when the current step started, the start of a ramp
+    pub step_start_temp: f64,
+
+    // All steps are done, the last temperature is kept
+    pub finished: bool,
+
+    // The position does not advance while paused
+    #[serde(default)]
+    pub paused: bool,
+
+    // Time the position was last saved, to account for time the controller was down
+    #[serde(default)]
+    pub saved_at: Option<DateTime<Utc>>,
+}
+
+/// Position in the active profile for the status API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProfileStatus {
+    pub name: String,
+    pub step: usize,
+    pub steps: usize,
+    pub step_elapsed_ms: f64,
+
+    // Duration of the current step, None if it ends on a condition
+    pub step_duration_ms: Option<f64>,
+
+    // Progress of the current step between 0 and 1, None if it ends on a condition
+    pub step_progress: Option<f64>,
+
+    pub finished: bool,
+    pub paused: bool,
+}
+
+impl ActiveProfile {
+    /// Start a profile, a ramp in the first step starts at the current `setpoint`
+    pub fn start(id: Option<u64>, profile: Profile, setpoint: f64) -> ActiveProfile {
+        info!("Starting profile {}", profile.name);
+        ActiveProfile {
+            id,
+            profile,
+            step: 0,
+            step_elapsed_ms: 0.0,
+            step_start_temp: setpoint,
+            finished: false,
+            paused: false,
+            saved_at: None,
+        }
+    }
+
+    fn current_step(&self) -> &Step {
+        &self.profile.steps[self.step]
+    }
+
+    /// Target temperature for the current position
+    pub fn setpoint(&self) -> f64 {
+        match *self.current_step() {
+            Step::Ramp {
+                temperature,
+                duration_ms,
+            } if !self.finished && duration_ms > 0.0 => {
+                let progress = (self.step_elapsed_ms / duration_ms).min(1.0);
+                self.step_start_temp + (temperature - self.step_start_temp) * progress
+            }
+            step => step.temperature(),
+        }
+    }
+
+    /// Advance the profile by `delta_ms`, returns true when the step changed.
+    /// Time left over after a step is carried over to the next steps.
+    pub fn advance(&mut self, delta_ms: f64, inside_temp: Option<f64>) -> bool {
+        if self.finished || self.paused {
+            return false;
+        }
+        self.step_elapsed_ms += delta_ms;
+        let mut changed = false;
+        while !self.finished {
+            match *self.current_step() {
+                Step::Hold { duration_ms, .. } | Step::Ramp { duration_ms, .. }
+                    if self.step_elapsed_ms >= duration_ms =>
+                {
+                    let left_over_ms = self.step_elapsed_ms - duration_ms;
+                    self.next_step();
+                    self.step_elapsed_ms = left_over_ms;
+                }
+                Step::HoldUntil { condition, .. } if condition_met(condition, inside_temp) => {
+                    self.next_step();
+                }
+                _ => break,
+            }
+            changed = true;
+        }
+        changed
+    }
+
+    pub fn pause(&mut self) {
+        info!("Pausing profile {}", self.profile.name);
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        info!("Resuming profile {}", self.profile.name);
+        self.paused = false;
+    }
+
+    /// Continue with the next step, or finish after the last one
+    pub fn next_step(&mut self) {
+        self.step_start_temp = self.setpoint();
+        if self.step + 1 < self.profile.steps.len() {
+            self.step += 1;
+            self.step_elapsed_ms = 0.0;
+            info!(
+                "Profile {} continues with step {}: {:?}",
+                self.profile.name,
+                self.step,
+                self.current_step()
+            );
+        } else {
+            info!("Profile {} finished", self.profile.name);
+            self.finished = true;
+        }
+    }
+
+    pub fn status(&self) -> ProfileStatus {
+        let step_duration_ms = self.current_step().duration_ms();
+        ProfileStatus {
+            name: self.profile.name.clone(),
+            step: self.step,
+            steps: self.profile.steps.len(),
+            step_elapsed_ms: self.step_elapsed_ms,
+            step_duration_ms,
+            step_progress: step_duration_ms.map(|duration_ms| {
+                if self.finished || duration_ms <= 0.0 {
+                    1.0
+                } else {
+                    (self.step_elapsed_ms / duration_ms).min(1.0)
+                }
+            }),
+            finished: self.finished,
+            paused: self.paused,
+        }
+    }
+}
+
+fn condition_met(condition: Condition, inside_temp: Option<f64>) -> bool {
+    match (condition, inside_temp) {
+        (Condition::Below { temperature }, Some(inside_temp)) => inside_temp <= temperature,
+        (Condition::Above { temperature }, Some(inside_temp)) => inside_temp >= temperature,
+        _ => false,
+    }
+}
+
+/// Read the active profile, None if there is no profile file.
+/// The time since the profile was saved is added to a running profile,
+/// so it continues where it would have been without the restart.
+pub fn read_active_profile(path: &Path) -> Result<Option<ActiveProfile>> {
+    match File::open(path) {
+        Ok(f) => {
+            let mut active: Option<ActiveProfile> = serde_json::from_reader(BufReader::new(f))
+                .with_context(|| format!("invalid profile file {}", path.display()))?;
+            if let Some(active) = &mut active {
+                active.profile.validate()?;
+                if active.step >= active.profile.steps.len() {
+                    bail!("step of the active profile is out of range");
+                }
+                if let Some(saved_at) = active.saved_at {
+                    let down_ms = (Utc::now() - saved_at).num_milliseconds().max(0) as f64;
+                    info!(
+                        "Resuming profile {} at step {}, {} ms after it was saved",
+                        active.profile.name, active.step, down_ms
+                    );
+                    active.advance(down_ms, None);
+                }
+            }
+            Ok(active)
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+pub fn write_active_profile(path: &Path, active: Option<&ActiveProfile>) -> Result<()> {
+    let active = active.map(|active| ActiveProfile {
+        saved_at: Some(Utc::now()),
+        ..active.clone()
+    });
+    serde_json::to_writer(&File::create(path)?, &active)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR_MS: f64 = 3600000.0;
+
+    fn profile(steps: Vec<Step>) -> Profile {
+        Profile {
+            name: "test".to_string(),
+            steps,
+        }
+    }
+
+    fn schedule() -> Profile {
+        profile(vec![
+            Step::Hold {
+                temperature: 18.0,
+                duration_ms: HOUR_MS,
+            },
+            Step::Ramp {
+                temperature: 22.0,
+                duration_ms: 2.0 * HOUR_MS,
+            },
+            Step::Hold {
+                temperature: 22.0,
+                duration_ms: HOUR_MS,
+            },
+        ])
+    }
+
+    #[test]
+    fn ramp_starts_at_setpoint() {
+        let ramp = profile(vec![Step::Ramp {
+            temperature: 10.0,
+            duration_ms: HOUR_MS,
+        }]);
+        let mut active = ActiveProfile::start(None, ramp, 20.0);
+        assert_eq!(active.setpoint(), 20.0);
+        active.advance(HOUR_MS / 2.0, Some(4.0));
+        assert_eq!(active.setpoint(), 15.0);
+    }
+
+    #[test]
+    fn carries_time_over_to_next_steps() {
+        let mut active = ActiveProfile::start(None, schedule(), 18.0);
+        assert!(!active.advance(HOUR_MS / 2.0, None));
+        // Half an hour left in the hold, the rest goes into the ramp
+        assert!(active.advance(HOUR_MS, None));
+        assert_eq!(active.step, 1);
+        assert_eq!(active.step_elapsed_ms, HOUR_MS / 2.0);
+        assert_eq!(active.setpoint(), 19.0);
+
+        // Past the ramp and the last hold at once
+        assert!(active.advance(3.0 * HOUR_MS, None));
+        assert!(active.finished);
+        assert_eq!(active.setpoint(), 22.0);
+    }
+
+    #[test]
+    fn hold_until_ends_on_condition() {
+        let crash = profile(vec![
+            Step::HoldUntil {
+                temperature: 2.0,
+                condition: Condition::Below { temperature: 3.0 },
+            },
+            Step::Hold {
+                temperature: 2.0,
+                duration_ms: HOUR_MS,
+            },
+        ]);
+        let mut active = ActiveProfile::start(None, crash, 18.0);
+        assert!(!active.advance(10.0 * HOUR_MS, Some(3.5)));
+        assert!(!active.advance(HOUR_MS, None));
+        assert_eq!(active.step, 0);
+        assert!(active.advance(1000.0, Some(3.0)));
+        assert_eq!(active.step, 1);
+        assert_eq!(active.step_elapsed_ms, 0.0);
+    }
+
+    #[test]
+    fn pause_stops_the_position() {
+        let mut active = ActiveProfile::start(None, schedule(), 18.0);
+        active.advance(HOUR_MS / 2.0, None);
+        active.pause();
+        assert!(!active.advance(10.0 * HOUR_MS, None));
+        assert_eq!(active.step, 0);
+        assert_eq!(active.step_elapsed_ms, HOUR_MS / 2.0);
+
+        active.resume();
+        assert!(active.advance(HOUR_MS / 2.0, None));
+        assert_eq!(active.step, 1);
+    }
+
+    #[test]
+    fn catches_up_after_restart() {
+        let path = std::env::temp_dir().join(format!("frust-profile-{}.json", std::process::id()));
+        let mut active = ActiveProfile::start(Some(3), schedule(), 18.0);
+        active.advance(HOUR_MS / 2.0, None);
+        // Saved an hour and a half ago
+        let saved = ActiveProfile {
+            saved_at: Some(Utc::now() - chrono::Duration::minutes(90)),
+            ..active
+        };
+        serde_json::to_writer(&File::create(&path).unwrap(), &Some(saved)).unwrap();
+
+        let resumed = read_active_profile(&path).unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(resumed.id, Some(3));
+        assert_eq!(resumed.step, 1);
+        // An hour into the ramp, give or take the time the test took
+        assert!((resumed.step_elapsed_ms - HOUR_MS).abs() < 60000.0);
+        assert!((resumed.setpoint() - 20.0).abs() < 0.1);
+    }
+}
@@ -0,0 +1,303 @@
+//! First-order thermal model of a fridge for running frust without hardware.
+//! The fridge air exchanges heat with the room and with the contents (beer),
+//! the compressor cools the air and the heater warms it.
+//! A `SimulatedClock` advances the model and can run faster than real time.
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+
+use crate::clock::Clock;
+use crate::controller::{Actuators, Outputs};
+use crate::probes::{ProbeError, SensorReader, TemperatureSensor};
+
+// Model is integrated in steps of one second
+const STEP_S: f64 = 1.0;
+
+#[derive(Debug, Copy, Clone)]
+pub struct ThermalParameters {
+    // Time constant of the heat exchange between the room and the fridge air (s)
+    pub wall_tau_s: f64,
+
+    // Time constant of the fridge air towards the contents (s)
+    pub air_tau_s: f64,
+
+    // Time constant of the contents towards the fridge air (s)
+    pub contents_tau_s: f64,
+
+    // Cooling of the fridge air while the compressor runs (°C/s)
+    pub compressor_rate: f64,
+
+    // Heating of the fridge air while the heater runs (°C/s)
+    pub heater_rate: f64,
+}
+
+impl Default for ThermalParameters {
+    fn default() -> ThermalParameters {
+        ThermalParameters {
+            wall_tau_s: 10800.0,
+            air_tau_s: 600.0,
+            contents_tau_s: 14400.0,
+            compressor_rate: 0.02,
+            heater_rate: 0.03,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ThermalModel {
+    pub parameters: ThermalParameters,
+    pub ambient_temp: f64,
+    pub air_temp: f64,
+    pub contents_temp: f64,
+    pub relays: Outputs,
+}
+
+impl ThermalModel {
+    /// Fridge and contents start at room temperature
+    pub fn new(parameters: ThermalParameters, ambient_temp: f64) -> ThermalModel {
+        ThermalModel {
+            parameters,
+            ambient_temp,
+            air_temp: ambient_temp,
+            contents_temp: ambient_temp,
+            relays: Outputs {
+                compressor: false,
+                heater: false,
+            },
+        }
+    }
+
+    /// Advance the model by `dt_s` seconds with the current relay states
+    pub fn step(&mut self, dt_s: f64) {
+        let p = &self.parameters;
+        let mut air_rate = (self.ambient_temp - self.air_temp) / p.wall_tau_s
+            + (self.contents_temp - self.air_temp) / p.air_tau_s;
+        if self.relays.compressor {
+            air_rate -= p.compressor_rate;
+        }
+        if self.relays.heater {
+            air_rate += p.heater_rate;
+        }
+        let contents_rate = (self.air_temp - self.contents_temp) / p.contents_tau_s;
+        self.air_temp += air_rate * dt_s;
+        self.contents_temp += contents_rate * dt_s;
+    }
+}
+
+/// Temperature in the model a simulated sensor measures
+#[derive(Debug, Copy, Clone)]
+pub enum Probe {
+    Contents,
+    Air,
+    Ambient,
+}
+
+pub struct SimulatedSensor {
+    name: String,
+    probe: Probe,
+    model: Arc<Mutex<ThermalModel>>,
+}
+
+impl TemperatureSensor for SimulatedSensor {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn read(&mut self) -> Result<f64, ProbeError> {
+        let model = self.model.lock().unwrap();
+        Ok(match self.probe {
+            Probe::Contents => model.contents_temp,
+            Probe::Air => model.air_temp,
+            Probe::Ambient => model.ambient_temp,
+        })
+    }
+}
+
+/// Relays switching the compressor and heater of the model
+pub struct SimulatedRelays {
+    model: Arc<Mutex<ThermalModel>>,
+}
+
+impl Actuators for SimulatedRelays {
+    fn apply(&mut self, outputs: &Outputs) -> Result<()> {
+        self.model.lock().unwrap().relays = *outputs;
+        Ok(())
+    }
+}
+
+/// Clock that advances the model while sleeping,
+/// `acceleration` times faster than real time
+pub struct SimulatedClock {
+    model: Arc<Mutex<ThermalModel>>,
+    acceleration: f64,
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration.div_f64(self.acceleration));
+        let mut remaining = duration.as_secs_f64();
+        let mut model = self.model.lock().unwrap();
+        while remaining > 0.0 {
+            model.step(STEP_S.min(remaining));
+            remaining -= STEP_S;
+        }
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+/// Shared thermal model with the sensors, relays and clock to drive it
+#[derive(Clone)]
+pub struct Simulation {
+    model: Arc<Mutex<ThermalModel>>,
+    acceleration: f64,
+}
+
+impl Simulation {
+    pub fn new(ambient_temp: f64, acceleration: f64) -> Result<Simulation> {
+        // The clock divides by it, NaN or infinity would panic there
+        if !(acceleration.is_finite() && acceleration > 0.0) {
+            bail!(
+                "acceleration must be a positive number, got {}",
+                acceleration
+            );
+        }
+        Ok(Simulation {
+            model: Arc::new(Mutex::new(ThermalModel::new(
+                ThermalParameters::default(),
+                ambient_temp,
+            ))),
+            acceleration,
+        })
+    }
+
+    /// Sensors for the contents (`inside`), fridge air (`air`) and room (`outside`)
+    pub fn sensors(&self) -> Vec<SensorReader> {
+        [
+            ("inside", Probe::Contents),
+            ("air", Probe::Air),
+            ("outside", Probe::Ambient),
+        ]
+        .iter()
+        .map(|(name, probe)| {
+            SensorReader::with_defaults(Box::new(SimulatedSensor {
+                name: name.to_string(),
+                probe: *probe,
+                model: self.model.clone(),
+            }))
+        })
+        .collect()
+    }
+
+    pub fn relays(&self) -> SimulatedRelays {
+        SimulatedRelays {
+            model: self.model.clone(),
+        }
+    }
+
+    pub fn clock(&self) -> SimulatedClock {
+        SimulatedClock {
+            model: self.model.clone(),
+            acceleration: self.acceleration,
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::from_secs(0)),
+        }
+    }
+
+    /// Turn off both simulated relays
+    pub fn shutdown(&self) {
+        // Also called from the panic hook, where the lock might be poisoned
+        if let Ok(mut model) = self.model.lock() {
+            model.relays = Outputs {
+                compressor: false,
+                heater: false,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::controller::{
+        ControlStrategy, Controller, FeedForward, Inputs, OperationMode, RelayFeedback, Timing,
+    };
+    use crate::pid::{Gains, PidLimits};
+
+    #[test]
+    fn rejects_invalid_acceleration() {
+        for acceleration in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(Simulation::new(20.0, acceleration).is_err());
+        }
+    }
+
+    // The controller on the model, read and stepped like the control loop does
+    #[test]
+    fn settles_near_the_setpoint() {
+        let simulation = Simulation::new(20.0, 1e9).unwrap();
+        let mut sensors = simulation.sensors();
+        let mut relays = simulation.relays();
+        let clock = simulation.clock();
+        let mut controller = Controller::new();
+        let mut inside = Vec::new();
+        let start = clock.now();
+        let mut now = start;
+        // Two days, the contents take hours to follow the air
+        for _ in 0..172800 {
+            let delta_ms = clock.now().duration_since(now).as_millis() as f64;
+            now = clock.now();
+            let temperatures: BTreeMap<String, f64> = sensors
+                .iter_mut()
+                .map(|sensor| {
+                    sensor.update(now);
+                    (sensor.name().to_string(), sensor.value().unwrap())
+                })
+                .collect();
+            let inputs = Inputs {
+                inside_temp: Some(temperatures["inside"]),
+                outside_temp: Some(temperatures["outside"]),
+                air_temp: Some(temperatures["air"]),
+                target_temp: 12.0,
+                operation_mode: OperationMode::Auto,
+                relay: None,
+                manual_override: None,
+                door: None,
+                relay_feedback: RelayFeedback::default(),
+                strategy: ControlStrategy::Pid,
+                gains: Gains {
+                    p: 8.0,
+                    i: 0.005,
+                    d: 0.0,
+                },
+                pid_limits: PidLimits::default(),
+                cascade: None,
+                feed_forward: FeedForward::default(),
+                timing: Timing::default(),
+            };
+            relays.apply(&controller.step(&inputs, delta_ms)).unwrap();
+            inside.push(temperatures["inside"]);
+            clock.sleep(Duration::from_millis(1000));
+        }
+        // The last six hours stay close to the target
+        let last = &inside[inside.len() - 21600..];
+        let min = last.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = last.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        assert!(
+            min > 11.5 && max < 12.5,
+            "inside between {} and {}",
+            min,
+            max
+        );
+    }
+}
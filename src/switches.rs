@@ -0,0 +1,111 @@
+//! Digital inputs: the reed switch of the fridge door and the current sense
+//! feedback of the relays. Every input is watched for edges on its own thread,
+//! which logs the change and keeps the latest value for the control loop.
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::Result;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::gpio::{Edge, PinConfig};
+
+// Read the value when no edge came for this long, in case an edge was missed
+const EDGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// GPIO pin of a digital input
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct InputConfig {
+    #[serde(flatten)]
+    pub pin: PinConfig,
+
+    // The input is on when the pin is low
+    #[serde(default)]
+    pub active_low: bool,
+}
+
+/// Reed switch of the fridge door, on when the door is open
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DoorConfig {
+    #[serde(flatten)]
+    pub input: InputConfig,
+
+    // Raise an alarm when the door is open for longer than this
+    #[serde(default = "default_left_open_ms")]
+    pub left_open_ms: f64,
+}
+
+fn default_left_open_ms() -> f64 {
+    // Five minutes, long enough to take a sample or add dry hops
+    300000.0
+}
+
+impl DoorConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.left_open_ms.is_finite() || self.left_open_ms <= 0.0 {
+            anyhow::bail!("left_open_ms of the door must be larger than 0");
+        }
+        Ok(())
+    }
+}
+
+/// Latest value of an input that is watched on its own thread
+#[derive(Debug, Clone)]
+pub struct Switch {
+    on: Arc<AtomicBool>,
+}
+
+impl Switch {
+    /// Open the input of `name` and watch it for edges.
+    /// `messages` are logged when the input turns off and on.
+    pub fn watch(name: &str, config: &InputConfig, messages: [String; 2]) -> Result<Switch> {
+        let mut pin = config
+            .pin
+            .open_input(name, config.active_low, Edge::BothEdges)?;
+        let on = Arc::new(AtomicBool::new(pin.get_value()? != 0));
+        info!("{}", messages[on.load(Ordering::SeqCst) as usize]);
+
+        let watched = on.clone();
+        let name = name.to_string();
+        thread::Builder::new()
+            .name(format!("{} input", name))
+            .spawn(move || loop {
+                let value = match pin.wait_for_edge(EDGE_TIMEOUT) {
+                    Ok(Some(value)) => Ok(value),
+                    Ok(None) => pin.get_value(),
+                    Err(e) => Err(e),
+                };
+                match value {
+                    Ok(value) => {
+                        let value = value != 0;
+                        if watched.swap(value, Ordering::SeqCst) != value {
+                            info!("{}", messages[value as usize]);
+                        }
+                    }
+                    Err(e) => {
+                        error!("Could not read {} input: {:?}", name, e);
+                        thread::sleep(EDGE_TIMEOUT);
+                    }
+                }
+            })?;
+        Ok(Switch { on })
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on.load(Ordering::SeqCst)
+    }
+}
+
+/// Watched inputs, None when they are not configured
+#[derive(Debug, Clone, Default)]
+pub struct Switches {
+    pub door: Option<Switch>,
+    pub compressor_feedback: Option<Switch>,
+    pub heater_feedback: Option<Switch>,
+}
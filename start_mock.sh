@@ -6,4 +6,4 @@ set -x
 export INSIDE_SENSOR=test/mock_sensor
 export OUTSIDE_SENSOR=test/mock_sensor
 export TOKEN=test-token
-cargo run
\ No newline at end of file
+cargo run -- --fake-gpio gpio.jsonl
\ No newline at end of file
@@ -0,0 +1,7 @@
+#!/bin/bash
+
+set -e
+set -x
+
+export TOKEN=test-token
+cargo run -- --simulate --acceleration 60